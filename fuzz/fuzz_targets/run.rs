@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::time::Duration;
+
+use handrit::{FsPolicy, Limits, Machine};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|source: &str| {
+    let mut machine = Machine::new();
+    machine.set_fs_policy(FsPolicy::Deny);
+    machine.set_limits(Limits {
+        steps: Some(10_000),
+        digits: Some(1_000),
+        length: Some(10_000),
+        time: Some(Duration::from_millis(100)),
+        ..Limits::default()
+    });
+    let _ = machine.run(source);
+    let _ = machine.run_rpn(source);
+});
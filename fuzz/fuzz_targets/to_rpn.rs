@@ -0,0 +1,8 @@
+#![no_main]
+
+use handrit::Machine;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|source: &str| {
+    let _ = Machine::new().to_rpn(source);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use handrit::Machine;
+use libfuzzer_sys::fuzz_target;
+
+// The tokenizer on its own, through the lexemes it records.
+fuzz_target!(|source: &str| {
+    let _ = Machine::new().lexemes(source);
+});
@@ -0,0 +1,243 @@
+use crate::error::Error;
+use crate::rpn::Token;
+
+use bigdecimal::BigDecimal;
+use std::str::FromStr;
+
+/// Reads CSV text as a list of rows keyed by the header. Unquoted fields that
+/// look like numbers become numbers; quoted fields always stay strings.
+pub fn from_csv(text: &str) -> Result<Token, Error> {
+    let mut records = records(text)?.into_iter();
+    let header: Vec<String> = match records.next() {
+        Some(header) => header.into_iter().map(|field| field.text).collect(),
+        None => return Ok(Token::List(Vec::new())),
+    };
+    let mut rows = Vec::new();
+    for (line, record) in records.enumerate() {
+        if record.len() != header.len() {
+            return Err(Error::data(format!(
+                "CSV row {} has {} fields, the header has {}",
+                line + 2,
+                record.len(),
+                header.len()
+            )));
+        }
+        let row = header
+            .iter()
+            .zip(record)
+            .map(|(key, field)| {
+                Token::Entry(Box::new(Token::String(key.clone())), Box::new(field.value()))
+            })
+            .collect();
+        rows.push(Token::List(row));
+    }
+    Ok(Token::List(rows))
+}
+
+/// Writes a list of rows as CSV. Keyed rows share a header made of every key
+/// in order of appearance, leaving missing fields empty; rows without keys
+/// are written as they are, without a header.
+pub fn to_csv(token: &Token) -> Result<String, Error> {
+    let rows = match token {
+        Token::List(rows) => rows,
+        other => return Err(not_a_table(other)),
+    };
+    let mut keyed_rows = Vec::new();
+    for row in rows {
+        match row {
+            Token::List(items) => keyed_rows.push(keyed(items)),
+            other => return Err(not_a_table(other)),
+        }
+    }
+    let mut out = String::new();
+    if rows.iter().any(|row| matches!(row, Token::List(items) if has_entries(items))) {
+        let mut header: Vec<String> = Vec::new();
+        for (key, _) in keyed_rows.iter().flatten() {
+            if !header.contains(key) {
+                header.push(key.clone());
+            }
+        }
+        let fields: Vec<String> = header.iter().map(|key| quote_field(key)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+        for row in &keyed_rows {
+            let mut fields = Vec::new();
+            for key in &header {
+                fields.push(match row.iter().find(|(k, _)| k == key) {
+                    Some((_, value)) => field_text(value)?,
+                    None => String::new(),
+                });
+            }
+            out.push_str(&fields.join(","));
+            out.push('\n');
+        }
+    } else {
+        for row in &keyed_rows {
+            let fields = row
+                .iter()
+                .map(|(_, value)| field_text(value))
+                .collect::<Result<Vec<_>, _>>()?;
+            out.push_str(&fields.join(","));
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+fn not_a_table(token: &Token) -> Error {
+    Error::type_error(format!("cannot write {} as CSV rows", token))
+}
+
+fn has_entries(items: &[Token]) -> bool {
+    items.iter().any(|item| matches!(item, Token::Entry(_, _)))
+}
+
+/// Pairs each element of a row with its key, unkeyed elements taking their
+/// index as JSON objects do.
+fn keyed(items: &[Token]) -> Vec<(String, &Token)> {
+    let mut next_index = 0;
+    items
+        .iter()
+        .map(|item| match item {
+            Token::Entry(key, value) => match key.as_ref() {
+                Token::String(s) => (s.clone(), value.as_ref()),
+                other => (other.to_string(), value.as_ref()),
+            },
+            value => {
+                next_index += 1;
+                ((next_index - 1).to_string(), value)
+            }
+        })
+        .collect()
+}
+
+fn field_text(value: &Token) -> Result<String, Error> {
+    match value {
+        Token::Number(_) => Ok(value.to_string()),
+        Token::String(s) if looks_numeric(s) => Ok(format!("\"{}\"", s)),
+        Token::String(s) => Ok(quote_field(s)),
+        other => Err(Error::type_error(format!("cannot write {} as a CSV field", other))),
+    }
+}
+
+fn quote_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn looks_numeric(s: &str) -> bool {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let mut parts = digits.splitn(2, '.');
+    let whole = parts.next().unwrap_or_default();
+    let fraction = parts.next();
+    !whole.is_empty()
+        && whole.chars().all(|ch| ch.is_ascii_digit())
+        && fraction.is_none_or(|f| !f.is_empty() && f.chars().all(|ch| ch.is_ascii_digit()))
+}
+
+struct Field {
+    text: String,
+    quoted: bool,
+}
+
+impl Field {
+    fn value(self) -> Token {
+        if !self.quoted && looks_numeric(&self.text) {
+            if let Ok(number) = BigDecimal::from_str(&self.text) {
+                return Token::Number(number);
+            }
+        }
+        Token::String(self.text)
+    }
+}
+
+/// Splits CSV text into records, skipping blank lines.
+fn records(text: &str) -> Result<Vec<Vec<Field>>, Error> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = Field { text: String::new(), quoted: false };
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if field.text.is_empty() && !field.quoted => {
+                field.quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.text.push('"');
+                        }
+                        Some('"') => break,
+                        Some(ch) => {
+                            if ch == '\n' {
+                                line += 1;
+                            }
+                            field.text.push(ch);
+                        }
+                        None => {
+                            return Err(Error::data(format!(
+                                "unterminated quoted CSV field on line {}",
+                                line
+                            )))
+                        }
+                    }
+                }
+            }
+            ',' => record.push(std::mem::replace(
+                &mut field,
+                Field { text: String::new(), quoted: false },
+            )),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                line += 1;
+                end_record(&mut records, &mut record, &mut field);
+            }
+            ch => field.text.push(ch),
+        }
+    }
+    end_record(&mut records, &mut record, &mut field);
+    Ok(records)
+}
+
+fn end_record(records: &mut Vec<Vec<Field>>, record: &mut Vec<Field>, field: &mut Field) {
+    let field = std::mem::replace(field, Field { text: String::new(), quoted: false });
+    if record.is_empty() && field.text.is_empty() && !field.quoted {
+        return; // blank line
+    }
+    record.push(field);
+    records.push(std::mem::take(record));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn test_from_csv() {
+        let text = "name,qty,price\r\nwidget,3,0.10\n\"a, \"\"b\"\"\",-2,\"007\"\n\n";
+        assert_eq!(
+            from_csv(text).unwrap().to_string(),
+            r#"[{name: "widget", qty: 3, price: 0.10} {name: "a, \"b\"", qty: -2, price: "007"}]"#
+        );
+        assert_eq!(from_csv("").unwrap().to_string(), "[]");
+        assert_eq!(from_csv("a,b\n1,2,3").unwrap_err().kind, ErrorKind::Data);
+        assert_eq!(from_csv("a\n\"1").unwrap_err().kind, ErrorKind::Data);
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let text = "name,qty,price\nwidget,3,0.10\n\"a, \"\"b\"\"\",-2,\"007\"\n";
+        assert_eq!(to_csv(&from_csv(text).unwrap()).unwrap(), text);
+        let rows = crate::rpn::Machine::new().run("[{a: 1} {b: \"x\"} [1 2]]").unwrap();
+        assert_eq!(to_csv(&rows).unwrap(), "a,b,0,1\n1,,,\n,x,,\n,,1,2\n");
+        let plain = crate::rpn::Machine::new().run("[[1 2] [3 4]]").unwrap();
+        assert_eq!(to_csv(&plain).unwrap(), "1,2\n3,4\n");
+        let nested = crate::rpn::Machine::new().run("[[[1]]]").unwrap();
+        assert_eq!(to_csv(&nested).unwrap_err().kind, ErrorKind::Type);
+    }
+}
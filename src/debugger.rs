@@ -0,0 +1,271 @@
+use crate::error::{Error, Span};
+use crate::rpn::{Machine, Step, Token};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Where a debugger pauses: on entering an operator, named or defined on a
+/// line of the source, counted from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    Operator(String),
+    Line(usize),
+}
+
+impl Breakpoint {
+    /// Reads a line number or an operator name.
+    pub fn parse(text: &str) -> Breakpoint {
+        match text.parse() {
+            Ok(line) => Breakpoint::Line(line),
+            Err(_) => Breakpoint::Operator(text.to_string()),
+        }
+    }
+}
+
+/// How far a run goes before pausing again, breakpoints aside.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// Pause before the next step.
+    Step,
+    /// Pause at the next step at most this deep, after the calls under way.
+    Next(usize),
+    /// Pause once the call this deep is over.
+    Out(usize),
+    Continue,
+}
+
+struct State {
+    mode: Mode,
+    breakpoints: Vec<Breakpoint>,
+    /// The depth of the last step, to tell when a call starts.
+    depth: usize,
+    source: String,
+}
+
+const HELP: &str = "s(tep) into calls, n(ext) over them, o(ut) of this one, c(ontinue) \
+    to a breakpoint, b(reak) NAME|LINE, d(elete) NAME|LINE, p(rint) the stack, f(rames) \
+    and their bindings, a(bort)";
+
+/// An interactive debugger: it pauses a run and reads commands, one line
+/// each, until one resumes it. `input` gives the next command, `None` at
+/// the end of the input, which lets the run go on; `output` writes a line.
+pub struct Debugger {
+    state: RefCell<State>,
+    input: RefCell<Input>,
+    output: RefCell<Output>,
+}
+
+type Input = Box<dyn FnMut() -> Option<String>>;
+type Output = Box<dyn FnMut(&str)>;
+
+impl Debugger {
+    pub fn new(
+        input: impl FnMut() -> Option<String> + 'static,
+        output: impl FnMut(&str) + 'static,
+    ) -> Rc<Debugger> {
+        Rc::new(Debugger {
+            state: RefCell::new(State {
+                mode: Mode::Step,
+                breakpoints: Vec::new(),
+                depth: 0,
+                source: String::new(),
+            }),
+            input: RefCell::new(Box::new(input)),
+            output: RefCell::new(Box::new(output)),
+        })
+    }
+
+    pub fn add_breakpoint(&self, breakpoint: Breakpoint) {
+        self.state.borrow_mut().breakpoints.push(breakpoint);
+    }
+
+    /// Makes `machine` pause in this debugger.
+    pub fn attach(self: &Rc<Self>, machine: &mut Machine) {
+        let debugger = self.clone();
+        machine.set_debugger(Some(Rc::new(move |step: &Step| debugger.pause(step))));
+    }
+
+    /// Gets ready for a run of `source`, the text line breakpoints refer
+    /// to: it pauses before the first step unless there are breakpoints.
+    pub fn start(&self, source: &str) {
+        let mut state = self.state.borrow_mut();
+        state.mode = if state.breakpoints.is_empty() { Mode::Step } else { Mode::Continue };
+        state.depth = 0;
+        state.source = source.to_string();
+    }
+
+    fn write(&self, line: &str) {
+        (self.output.borrow_mut())(line);
+    }
+
+    fn pause(&self, step: &Step) -> Result<(), Error> {
+        let stop = {
+            let mut state = self.state.borrow_mut();
+            let entered = step.depth > state.depth;
+            state.depth = step.depth;
+            let hit = |breakpoint: &Breakpoint| match breakpoint {
+                Breakpoint::Operator(name) => step.operator(step.depth) == Some(name.as_str()),
+                Breakpoint::Line(line) => step
+                    .definition(step.depth)
+                    .is_some_and(|span| lines(&state.source, span).contains(line)),
+            };
+            let arrived = match state.mode {
+                Mode::Step => true,
+                Mode::Next(depth) => step.depth <= depth,
+                Mode::Out(depth) => step.depth < depth,
+                Mode::Continue => false,
+            };
+            arrived || (entered && state.breakpoints.iter().any(hit))
+        };
+        if !stop {
+            return Ok(());
+        }
+        let place = step.operator(step.depth).unwrap_or("top level");
+        self.write(&format!("{} (depth {}), next: {:#}", place, step.depth, step.token));
+        loop {
+            let Some(line) = (self.input.borrow_mut())() else {
+                self.state.borrow_mut().mode = Mode::Continue;
+                return Ok(());
+            };
+            let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+                Some((command, argument)) => (command.to_string(), argument.trim().to_string()),
+                None => (line.trim().to_string(), String::new()),
+            };
+            let mode = match command.as_str() {
+                "s" | "step" => Mode::Step,
+                "n" | "next" => Mode::Next(step.depth),
+                "o" | "out" => Mode::Out(step.depth),
+                "c" | "continue" => Mode::Continue,
+                "b" | "break" if !argument.is_empty() => {
+                    self.add_breakpoint(Breakpoint::parse(&argument));
+                    self.write(&format!("breakpoint at {}", argument));
+                    continue;
+                }
+                "d" | "delete" if !argument.is_empty() => {
+                    let breakpoint = Breakpoint::parse(&argument);
+                    self.state.borrow_mut().breakpoints.retain(|other| *other != breakpoint);
+                    continue;
+                }
+                "p" | "print" => {
+                    let stack: Vec<String> = step.stack.iter().map(Token::to_string).collect();
+                    self.write(&format!("stack: {}", stack.join(" ")));
+                    continue;
+                }
+                "f" | "frames" => {
+                    self.write_frames(step);
+                    continue;
+                }
+                "a" | "abort" => return Err(Error::eval("aborted in the debugger")),
+                _ => {
+                    self.write(HELP);
+                    continue;
+                }
+            };
+            self.state.borrow_mut().mode = mode;
+            return Ok(());
+        }
+    }
+
+    /// Writes each frame, innermost last, with its bindings sorted by name.
+    fn write_frames(&self, step: &Step) {
+        for depth in 0..=step.depth {
+            self.write(&format!("#{} {}", depth, step.operator(depth).unwrap_or("globals")));
+            let mut bindings: Vec<_> = step.bindings(depth).into_iter().flatten().collect();
+            bindings.sort_by_key(|&(name, _)| name);
+            for (name, value) in bindings {
+                self.write(&format!("  {} = {}", name, value));
+            }
+        }
+    }
+}
+
+/// The lines, from 1, a span of `source` covers.
+fn lines(source: &str, span: Span) -> std::ops::RangeInclusive<usize> {
+    let line_at = |offset: usize| {
+        source.get(..offset).map_or(0, |text| text.matches('\n').count()) + 1
+    };
+    line_at(span.start)..=line_at(span.end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    /// Runs `source` in a debugger fed `commands`, returning the result and
+    /// what the debugger wrote.
+    fn debug(
+        machine: &mut Machine,
+        source: &str,
+        breakpoints: &[&str],
+        commands: &[&str],
+    ) -> (Result<String, Error>, Vec<String>) {
+        let mut commands: Vec<String> = commands.iter().rev().map(|c| c.to_string()).collect();
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let log = written.clone();
+        let debugger = Debugger::new(
+            move || commands.pop(),
+            move |line: &str| log.borrow_mut().push(line.to_string()),
+        );
+        for breakpoint in breakpoints {
+            debugger.add_breakpoint(Breakpoint::parse(breakpoint));
+        }
+        debugger.attach(machine);
+        debugger.start(source);
+        let result = machine.run(source).map(|value| value.to_string());
+        machine.set_debugger(None);
+        let written = written.borrow().clone();
+        (result, written)
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut machine = Machine::new();
+        machine.run("inc: {right + 1}").unwrap();
+        let (result, written) = debug(&mut machine, "2 * inc 3", &[], &["n", "n", "n", "c"]);
+        assert_eq!(result.unwrap(), "8");
+        let pauses = [
+            "top level (depth 0), next: 2",
+            "top level (depth 0), next: 3",
+            "top level (depth 0), next: inc",
+            "top level (depth 0), next: *",
+        ];
+        assert_eq!(written, pauses);
+        let (_, written) = debug(&mut machine, "2 * inc 3", &[], &["n", "n", "s", "p", "o", "c"]);
+        assert_eq!(written[3..], ["inc (depth 1), next: right", "stack: ", pauses[3]]);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut machine = Machine::new();
+        let fact = "fact: {(true: 1, false: (right * (this (right - 1)))) . (right <= 1)}";
+        machine.run(fact).unwrap();
+        let commands = ["f", "c", "p", "a"];
+        let (result, written) = debug(&mut machine, "fact 3", &["fact"], &commands);
+        assert_eq!(result.unwrap_err().kind, ErrorKind::Eval);
+        let expected = [
+            "fact (depth 1), next: [",
+            "#0 globals",
+            "#1 fact",
+            "  right = 3",
+            "fact (depth 2), next: [",
+            "stack: ",
+        ];
+        assert_eq!(written, expected);
+
+        let source = "[(a: {right})\n(b: {right + 1})\n(a 1) (b 1)]";
+        let (result, written) = debug(&mut machine, source, &["2"], &["f", "c"]);
+        assert_eq!(result.unwrap(), "[{right} {right + 1} 1 2]");
+        let expected = [
+            "b (depth 1), next: right",
+            "#0 globals",
+            "  a = {right}",
+            "  b = {right + 1}",
+            "#1 b",
+            "  right = 1",
+        ];
+        assert_eq!(written, expected);
+        let (result, written) = debug(&mut machine, "fact 2", &[], &[]);
+        assert_eq!((result.unwrap(), written.len()), ("2".to_string(), 1));
+    }
+}
@@ -0,0 +1,270 @@
+//! Examples in documents checked as tests. An example is a run of lines
+//! holding expressions, each on a line of its own or going on while a group
+//! is open, followed by `> value` lines with what the last one prints. The
+//! expressions before it run first; blank lines start a new example, and
+//! one without `>` lines only has to run without an error.
+//!
+//! ```text
+//! isub: {right - left}
+//! 1 isub 0
+//! > -1
+//! ```
+
+use crate::rpn::{continues, uncommented, Machine, Token};
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// An example found in a document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    /// The line, from 1, its first expression starts on.
+    pub line: usize,
+    pub expressions: Vec<String>,
+    /// What the last expression prints, lines joined by `\n`.
+    pub expected: Option<String>,
+}
+
+/// The examples in `text`, in order.
+pub fn cases(text: &str) -> Vec<Case> {
+    let mut cases = Vec::new();
+    let mut expressions = Vec::new();
+    let mut pending = String::new();
+    let mut expected: Vec<String> = Vec::new();
+    let mut line = 0;
+    for (index, text_line) in text.lines().enumerate() {
+        if let Some(value) = text_line.strip_prefix('>').filter(|_| pending.is_empty()) {
+            expected.push(uncommented(value).trim().to_string());
+            continue;
+        }
+        if !expected.is_empty() {
+            let expected = Some(std::mem::take(&mut expected).join("\n"));
+            let expressions = std::mem::take(&mut expressions);
+            cases.push(Case { line, expressions, expected });
+        }
+        if pending.is_empty() && text_line.trim().is_empty() {
+            let expressions = std::mem::take(&mut expressions);
+            cases.push(Case { line, expressions, expected: None });
+            continue;
+        }
+        if pending.is_empty() && expressions.is_empty() {
+            line = index + 1;
+        }
+        pending.push_str(text_line);
+        pending.push('\n');
+        if !continues(&pending) {
+            let expression = std::mem::take(&mut pending);
+            if !uncommented(&expression).trim().is_empty() {
+                expressions.push(expression.trim_end().to_string());
+            }
+        }
+    }
+    let expected = Some(expected.join("\n")).filter(|_| !expected.is_empty());
+    cases.push(Case { line, expressions, expected });
+    cases.retain(|case| !case.expressions.is_empty());
+    cases
+}
+
+/// Runs the expressions of `case` and checks what the last one prints,
+/// returning what it printed otherwise. An error prints as the CLI shows it.
+pub fn check(machine: &mut Machine, case: &Case) -> Result<(), String> {
+    let mut printed = String::new();
+    for expression in &case.expressions {
+        match machine.run(expression) {
+            Ok(value) => printed = value.to_string(),
+            Err(error) => {
+                printed = error.to_string();
+                if case.expected.is_none() {
+                    return Err(printed);
+                }
+                break;
+            }
+        }
+    }
+    match &case.expected {
+        Some(expected) if *expected != printed => Err(printed),
+        _ => Ok(()),
+    }
+}
+
+/// The lines of `expected` and `actual` side by side: those they share
+/// indented, the others marked `-` and `+`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(old), Some(new)) if old == new => out.push_str(&format!("  {}\n", old)),
+            (old, new) => {
+                if let Some(old) = old {
+                    out.push_str(&format!("- {}\n", old));
+                }
+                if let Some(new) = new {
+                    out.push_str(&format!("+ {}\n", new));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Checks the examples of a file, then runs the tests its expressions
+/// define, writing the failures to `out`; returns how many passed and
+/// failed.
+/// Outside .org files, lines without `> value` after them run too. With a
+/// filter only the tests whose name holds it run, and the examples only to
+/// define them; with `list` the tests are written out instead of run.
+pub fn test_file(
+    file: &Path,
+    filter: Option<&str>,
+    list: bool,
+    out: &mut impl Write,
+) -> io::Result<(usize, usize)> {
+    let text = match std::fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) => {
+            writeln!(out, "FAIL {}: {}", file.display(), e)?;
+            return Ok((0, 1));
+        }
+    };
+    let prose = file.extension().is_some_and(|extension| extension == "org");
+    let (mut passed, mut failed) = (0, 0);
+    // Operators defined by an example stay defined for the next ones; what
+    // they print isn't part of the report.
+    let mut machine = Machine::new();
+    machine.set_output(Rc::new(RefCell::new(io::sink())));
+    for case in cases(&text) {
+        if prose && case.expected.is_none() {
+            continue;
+        }
+        if filter.is_some() || list {
+            for expression in &case.expressions {
+                let _ = machine.run(expression);
+            }
+            continue;
+        }
+        match check(&mut machine, &case) {
+            Ok(()) if case.expected.is_some() => passed += 1,
+            Ok(()) => {}
+            Err(actual) => {
+                failed += 1;
+                writeln!(out, "FAIL {}:{}", file.display(), case.line)?;
+                for line in case.expressions.iter().flat_map(|e| e.lines()) {
+                    writeln!(out, "    {}", line)?;
+                }
+                write!(out, "{}", diff(case.expected.as_deref().unwrap_or(""), &actual))?;
+            }
+        }
+    }
+    let names: Vec<String> = machine.tests().into_iter().map(str::to_string).collect();
+    for name in names {
+        if filter.is_some_and(|filter| !name.contains(filter)) {
+            continue;
+        }
+        if list {
+            writeln!(out, "{}: {}", file.display(), name)?;
+            continue;
+        }
+        match machine.run_test(&name) {
+            Ok(_) => passed += 1,
+            Err(error) => {
+                failed += 1;
+                writeln!(out, "FAIL {}: test {}", file.display(), Token::String(name.clone()))?;
+                writeln!(out, "+ {}", error)?;
+            }
+        }
+    }
+    Ok((passed, failed))
+}
+
+/// Adds `path` to `files`, or the .hati and .org files under it, hidden
+/// directories left out. A file is always added when `named`, as one given
+/// on the command line.
+pub fn find_tests(path: &Path, named: bool, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if named || matches!(extension, Some("hati" | "org")) {
+            files.push(path.to_path_buf());
+        }
+        return;
+    };
+    let mut entries: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+    entries.sort();
+    for entry in entries {
+        let hidden = entry.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if !hidden {
+            find_tests(&entry, false, files);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cases() {
+        let text = "Prose.\n\nsum: {\n  left + right # both\n}\n1 sum 2\n> 3\n'a'\n>\"a\"\n\n\
+            a: 1\n\n# RPN: 1 2 +\n1 + 1\n> 2 # two\n> 3";
+        let found = cases(text);
+        let case = |line, expressions: &[&str], expected: Option<&str>| Case {
+            line,
+            expressions: expressions.iter().map(|e| e.to_string()).collect(),
+            expected: expected.map(str::to_string),
+        };
+        let expected = [
+            case(1, &["Prose."], None),
+            case(3, &["sum: {\n  left + right # both\n}", "1 sum 2"], Some("3")),
+            case(8, &["'a'"], Some("\"a\"")),
+            case(11, &["a: 1"], None),
+            case(14, &["1 + 1"], Some("2\n3")),
+        ];
+        assert_eq!(found, expected);
+        let mut machine = Machine::new();
+        assert_eq!(check(&mut machine, &found[1]), Ok(()));
+        assert_eq!(check(&mut machine, &found[2]), Ok(()));
+        assert_eq!(check(&mut machine, &found[3]), Ok(()));
+        assert_eq!(check(&mut machine, &found[4]), Err("2".to_string()));
+        let error = "name error at 4..8: undefined variable: totl";
+        let failing = case(1, &["1 + totl"], Some("1"));
+        assert_eq!(check(&mut machine, &failing), Err(error.to_string()));
+        let failing = case(1, &["1 + totl", "2"], None);
+        assert_eq!(check(&mut machine, &failing), Err(error.to_string()));
+        assert_eq!(diff("2\n3", "2"), "  2\n- 3\n");
+        assert_eq!(diff("1", "2"), "- 1\n+ 2\n");
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir().join(format!("handrit-doctest-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join(".hidden")).unwrap();
+        let file = dir.join("a.hati");
+        let text = "1 + 1\n> 3\n\n\"adds\" test {assert (1 + 1 = 2)}\n\"fails\" test {assert 0}\n";
+        std::fs::write(&file, text).unwrap();
+        std::fs::write(dir.join(".hidden").join("b.hati"), "").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+        let mut files = Vec::new();
+        find_tests(&dir, true, &mut files);
+        assert_eq!(files, [file.as_path()]);
+
+        let run = |filter, list| {
+            let mut out = Vec::new();
+            let counts = test_file(&file, filter, list, &mut out).unwrap();
+            (counts, String::from_utf8(out).unwrap())
+        };
+        let name = file.display();
+        let failures = format!(
+            "FAIL {}:1\n    1 + 1\n- 3\n+ 2\nFAIL {}: test \"fails\"\n\
+            + assertion error: got 0\n",
+            name, name
+        );
+        assert_eq!(run(None, false), ((1, 2), failures));
+        assert_eq!(run(Some("adds"), false), ((1, 0), String::new()));
+        let listed = format!("{}: adds\n{}: fails\n", name, name);
+        assert_eq!(run(None, true), ((0, 0), listed));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+/// Byte range of the source an error refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    Lex,
+    Name,
+    Eval,
+    Type,
+    Data,
+    Io,
+    Limit,
+    Permission,
+    Assertion,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Lex => write!(f, "lex"),
+            ErrorKind::Name => write!(f, "name"),
+            ErrorKind::Eval => write!(f, "eval"),
+            ErrorKind::Type => write!(f, "type"),
+            ErrorKind::Data => write!(f, "data"),
+            ErrorKind::Io => write!(f, "io"),
+            ErrorKind::Limit => write!(f, "limit"),
+            ErrorKind::Permission => write!(f, "permission"),
+            ErrorKind::Assertion => write!(f, "assertion"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Error {
+    pub fn lex(message: impl Into<String>, span: Span) -> Error {
+        Error {
+            kind: ErrorKind::Lex,
+            message: message.into(),
+            span: Some(span),
+        }
+    }
+
+    pub fn name(message: impl Into<String>, span: Span) -> Error {
+        Error {
+            kind: ErrorKind::Name,
+            message: message.into(),
+            span: Some(span),
+        }
+    }
+
+    pub fn eval(message: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Eval,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn type_error(message: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Type,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn data(message: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Data,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn io(message: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Io,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn limit(message: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Limit,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn permission(message: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Permission,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn assertion(message: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Assertion,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    /// Moves the span by `offset` bytes, for errors raised while reading a
+    /// piece of a larger source.
+    pub fn shifted(mut self, offset: usize) -> Error {
+        if let Some(span) = &mut self.span {
+            span.start += offset;
+            span.end += offset;
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} error at {}: {}", self.kind, span, self.message),
+            None => write!(f, "{} error: {}", self.kind, self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
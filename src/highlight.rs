@@ -0,0 +1,143 @@
+//! Highlighting of hati source as the tokenizer reads it, see
+//! [`crate::Machine::lexemes`], for terminals and web pages.
+
+use crate::error::Span;
+
+/// What a piece of source is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Number,
+    String,
+    /// A variable, or the name of a map literal key.
+    Name,
+    /// `left`, `right` and `this`.
+    Keyword,
+    /// An operator the language comes with.
+    Builtin,
+    /// An operator defined by a brace or registered from Rust.
+    Operator,
+    Comment,
+}
+
+impl Class {
+    /// The CSS class `to_html` marks the piece with.
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Number => "number",
+            Class::String => "string",
+            Class::Name => "name",
+            Class::Keyword => "keyword",
+            Class::Builtin => "builtin",
+            Class::Operator => "operator",
+            Class::Comment => "comment",
+        }
+    }
+
+    /// The SGR parameters `to_ansi` colors the piece with.
+    fn ansi(self) -> &'static str {
+        match self {
+            Class::Number => "33",
+            Class::String => "32",
+            Class::Name => "34",
+            Class::Keyword => "1;34",
+            Class::Builtin => "35",
+            Class::Operator => "1;35",
+            Class::Comment => "90",
+        }
+    }
+}
+
+/// `source` with escape sequences coloring each piece of it.
+pub fn to_ansi(source: &str, lexemes: &[(Span, Class)]) -> String {
+    render(source, lexemes, |out, text, class| match class {
+        Some(class) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", class.ansi(), text)),
+        None => out.push_str(text),
+    })
+}
+
+/// `source` as an HTML `pre` element, each piece in a `span` of its class,
+/// to style with CSS.
+pub fn to_html(source: &str, lexemes: &[(Span, Class)]) -> String {
+    let body = render(source, lexemes, |out, text, class| match class {
+        Some(class) => {
+            out.push_str(&format!("<span class=\"{}\">{}</span>", class.name(), escape(text)))
+        }
+        None => out.push_str(&escape(text)),
+    });
+    format!("<pre class=\"hati\">{}</pre>\n", body)
+}
+
+/// Writes `source` piece by piece, what lies between the lexemes too.
+fn render(
+    source: &str,
+    lexemes: &[(Span, Class)],
+    mut write: impl FnMut(&mut String, &str, Option<Class>),
+) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for &(span, class) in lexemes {
+        let before = source.get(last..span.start);
+        let (Some(before), Some(text)) = (before, source.get(span.start..span.end)) else {
+            continue;
+        };
+        write(&mut out, before, None);
+        write(&mut out, text, Some(class));
+        last = span.end;
+    }
+    write(&mut out, &source[last..], None);
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpn::Machine;
+
+    fn classes(source: &str) -> Vec<(&str, &'static str)> {
+        let lexemes = Machine::new().lexemes(source).unwrap();
+        lexemes.iter().map(|&(span, class)| (&source[span.start..span.end], class.name())).collect()
+    }
+
+    #[test]
+    fn test_classes() {
+        let source = "[(inc: {right + 1}) # add one\n (inc 'a') {k: \"v\"} r\"x\" this]";
+        let expected = [
+            ("inc", "name"),
+            (":", "builtin"),
+            ("right", "keyword"),
+            ("+", "builtin"),
+            ("1", "number"),
+            ("# add one", "comment"),
+            ("inc", "operator"),
+            ("'a'", "string"),
+            ("k", "name"),
+            (":", "builtin"),
+            ("\"v\"", "string"),
+            ("r\"x\"", "string"),
+            ("this", "keyword"),
+        ];
+        assert_eq!(classes(source), expected);
+        let expected = [("x", "name"), ("\"#\"", "string"), ("# c", "comment")];
+        assert_eq!(classes("x \"#\" # c"), expected);
+        let error = Machine::new().lexemes("x#y").unwrap_err();
+        assert_eq!(error.message, "unknown operator #");
+        assert_eq!(error.span, Some(Span::new(1, 2)));
+        assert_eq!(Machine::new().run("1 + 2 # + 3\n * 2").unwrap().to_string(), "5");
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "1 < x # \"b\"";
+        let lexemes = Machine::new().lexemes(source).unwrap();
+        let html = "<pre class=\"hati\"><span class=\"number\">1</span> \
+            <span class=\"builtin\">&lt;</span> <span class=\"name\">x</span> \
+            <span class=\"comment\"># &quot;b&quot;</span></pre>\n";
+        assert_eq!(to_html(source, &lexemes), html);
+        let ansi = "\x1b[33m1\x1b[0m \x1b[35m<\x1b[0m \x1b[34mx\x1b[0m \x1b[90m# \"b\"\x1b[0m";
+        assert_eq!(to_ansi(source, &lexemes), ansi);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::error::Error;
+use crate::limits::MAX_NESTING;
+use crate::rpn::Token;
+
+use bigdecimal::BigDecimal;
+use std::iter::Peekable;
+use std::str::{CharIndices, FromStr};
+
+/// Renders a value as JSON. Numbers keep every digit, lists become arrays and
+/// lists holding entries become objects, their unkeyed elements filed under
+/// their index.
+pub fn to_json(token: &Token) -> String {
+    let mut out = String::new();
+    write_json(&mut out, token);
+    out
+}
+
+fn write_json(out: &mut String, token: &Token) {
+    match token {
+        Token::Number(_) => out.push_str(&token.to_string()),
+        Token::String(s) => write_json_string(out, s),
+        Token::List(list) if list.iter().any(|item| matches!(item, Token::Entry(_, _))) => {
+            out.push('{');
+            let mut next_index = 0;
+            for (i, item) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                match item {
+                    Token::Entry(key, value) => {
+                        write_json_string(out, &key_text(key));
+                        out.push(':');
+                        write_json(out, value);
+                    }
+                    value => {
+                        write_json_string(out, &next_index.to_string());
+                        out.push(':');
+                        write_json(out, value);
+                        next_index += 1;
+                    }
+                }
+            }
+            out.push('}');
+        }
+        Token::List(list) => {
+            out.push('[');
+            for (i, item) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, item);
+            }
+            out.push(']');
+        }
+        Token::Entry(key, value) => {
+            out.push('{');
+            write_json_string(out, &key_text(key));
+            out.push(':');
+            write_json(out, value);
+            out.push('}');
+        }
+        other => write_json_string(out, &other.to_string()),
+    }
+}
+
+/// The largest exponent a number may have: `1e-1000000` already takes a
+/// million digits to print.
+const MAX_EXPONENT: u64 = 1_000_000;
+
+/// Reads a JSON document as a value. Objects become lists of entries keyed by
+/// strings, `true` and `false` become 1 and 0 and `null` the empty list.
+pub fn from_json(text: &str) -> Result<Token, Error> {
+    let mut parser = Parser { chars: text.char_indices().peekable(), len: text.len(), depth: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.peek() {
+        None => Ok(value),
+        Some(&(pos, _)) => Err(invalid(pos, "trailing characters")),
+    }
+}
+
+fn invalid(pos: usize, message: &str) -> Error {
+    Error::data(format!("invalid JSON at byte {}: {}", pos, message))
+}
+
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    len: usize,
+    /// Arrays and objects open around the value being read.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn position(&mut self) -> usize {
+        self.chars.peek().map_or(self.len, |&(pos, _)| pos)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|&(_, ch)| matches!(ch, ' ' | '\t' | '\n' | '\r')).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        self.skip_whitespace();
+        let pos = self.position();
+        match self.chars.next() {
+            Some((_, ch)) if ch == expected => Ok(()),
+            _ => Err(invalid(pos, &format!("expected {}", expected))),
+        }
+    }
+
+    /// Consumes `word` after its first character has been peeked.
+    fn keyword(&mut self, word: &str, value: Token) -> Result<Token, Error> {
+        let pos = self.position();
+        for expected in word.chars() {
+            match self.chars.next() {
+                Some((_, ch)) if ch == expected => {}
+                _ => return Err(invalid(pos, &format!("expected {}", word))),
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Token, Error> {
+        self.skip_whitespace();
+        let pos = self.position();
+        match self.chars.peek().map(|&(_, ch)| ch) {
+            Some(open @ ('{' | '[')) => {
+                if self.depth == MAX_NESTING {
+                    return Err(invalid(pos, &format!("nested more than {} deep", MAX_NESTING)));
+                }
+                self.depth += 1;
+                let value = if open == '{' { self.object() } else { self.array() };
+                self.depth -= 1;
+                value
+            }
+            Some('"') => Ok(Token::String(self.string()?)),
+            Some('t') => self.keyword("true", Token::Number(BigDecimal::from(1))),
+            Some('f') => self.keyword("false", Token::Number(BigDecimal::from(0))),
+            Some('n') => self.keyword("null", Token::List(Vec::new())),
+            Some('-' | '0'..='9') => self.number(),
+            Some(_) => Err(invalid(pos, "expected a value")),
+            None => Err(invalid(pos, "unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Token, Error> {
+        self.chars.next(); // skip the opening brace
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|&(_, ch)| ch == '}').is_some() {
+            return Ok(Token::List(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let pos = self.position();
+            if self.chars.peek().map(|&(_, ch)| ch) != Some('"') {
+                return Err(invalid(pos, "expected a string key"));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            let value = self.value()?;
+            entries.push(Token::Entry(Box::new(Token::String(key)), Box::new(value)));
+            self.skip_whitespace();
+            let pos = self.position();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, '}')) => return Ok(Token::List(entries)),
+                _ => return Err(invalid(pos, "expected , or }")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Token, Error> {
+        self.chars.next(); // skip the opening bracket
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|&(_, ch)| ch == ']').is_some() {
+            return Ok(Token::List(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            let pos = self.position();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, ']')) => return Ok(Token::List(items)),
+                _ => return Err(invalid(pos, "expected , or ]")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Token, Error> {
+        let pos = self.position();
+        let mut text = String::new();
+        while let Some((_, ch)) = self
+            .chars
+            .next_if(|&(_, ch)| ch.is_ascii_digit() || matches!(ch, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            text.push(ch);
+        }
+        match BigDecimal::from_str(&text) {
+            Ok(num) if num.as_bigint_and_exponent().1.unsigned_abs() <= MAX_EXPONENT => {
+                Ok(Token::Number(num))
+            }
+            Ok(_) => Err(invalid(pos, &format!("number out of range {}", text))),
+            Err(_) => Err(invalid(pos, &format!("invalid number {}", text))),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let start = self.position();
+        self.chars.next(); // skip the opening quote
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(s),
+                Some((pos, '\\')) => match self.chars.next() {
+                    Some((_, '"')) => s.push('"'),
+                    Some((_, '\\')) => s.push('\\'),
+                    Some((_, '/')) => s.push('/'),
+                    Some((_, 'b')) => s.push('\u{8}'),
+                    Some((_, 'f')) => s.push('\u{c}'),
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'u')) => s.push(self.unicode_escape(pos)?),
+                    _ => return Err(invalid(pos, "invalid escape")),
+                },
+                Some((pos, ch)) if (ch as u32) < 0x20 => {
+                    return Err(invalid(pos, "control character in string"))
+                }
+                Some((_, ch)) => s.push(ch),
+                None => return Err(invalid(start, "unterminated string")),
+            }
+        }
+    }
+
+    fn hex4(&mut self, pos: usize) -> Result<u32, Error> {
+        let mut code = 0;
+        for _ in 0..4 {
+            match self.chars.next().and_then(|(_, ch)| ch.to_digit(16)) {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(invalid(pos, "invalid \\u escape")),
+            }
+        }
+        Ok(code)
+    }
+
+    /// Reads the digits of a `\u` escape, pairing surrogates.
+    fn unicode_escape(&mut self, pos: usize) -> Result<char, Error> {
+        let high = self.hex4(pos)?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if self.chars.next().map(|(_, ch)| ch) != Some('\\')
+                || self.chars.next().map(|(_, ch)| ch) != Some('u')
+            {
+                return Err(invalid(pos, "unpaired surrogate"));
+            }
+            let low = self.hex4(pos)?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(invalid(pos, "unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| invalid(pos, "invalid \\u escape"))
+    }
+}
+
+/// Object keys are strings: string keys as they are, numbers in their notation.
+fn key_text(key: &Token) -> String {
+    match key {
+        Token::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpn::Machine;
+
+    fn json_of(expression: &str) -> String {
+        to_json(&Machine::new().run(expression).unwrap())
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(json_of("1 / 8"), "0.125");
+        assert_eq!(json_of("10 ** 30"), "1000000000000000000000000000000");
+        assert_eq!(json_of("-2.50"), "-2.50");
+        assert_eq!(json_of(r#""say \"hi\"\n\u{1}""#), r#""say \"hi\"\n\u0001""#);
+    }
+
+    #[test]
+    fn test_collections() {
+        assert_eq!(json_of("1, (2, 3), []"), "[1,[2,3],[]]");
+        assert_eq!(json_of("{a: 3, 0: 2, 1: 1}"), r#"{"a":3,"0":2,"1":1}"#);
+        assert_eq!(json_of(r#"{"k": [1 {x: "y"}]}"#), r#"{"k":[1,{"x":"y"}]}"#);
+        assert_eq!(json_of(r#""k": 1"#), r#"{"k":1}"#);
+    }
+
+    #[test]
+    fn test_from_json() {
+        let doc = r#" {"name": "x\ty\u00e9\ud83d\ude00", "n": [1, -2.5e3, 0.10],
+            "ok": true, "no": false, "none": null, "nested": {}} "#;
+        let value = from_json(doc).unwrap();
+        assert_eq!(
+            value.to_string(),
+            r#"{name: "x\tyé😀", n: [1 -2500 0.10], ok: 1, no: 0, none: [], nested: []}"#
+        );
+        assert_eq!(to_json(&from_json("[1,{\"a\":[]}]").unwrap()), "[1,{\"a\":[]}]");
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        for doc in ["", "[1,", "{1: 2}", "[1] 2", "\"abc", "tru", "[01x]", "\"\\ud800\""] {
+            let error = from_json(doc).unwrap_err();
+            assert_eq!(error.kind, crate::error::ErrorKind::Data, "{}", doc);
+        }
+        let deep = format!("{}{}", "[".repeat(MAX_NESTING + 1), "]".repeat(MAX_NESTING + 1));
+        let message = "invalid JSON at byte 100: nested more than 100 deep";
+        assert_eq!(from_json(&deep).unwrap_err().message, message);
+        assert!(from_json(&"[".repeat(100_000)).is_err());
+        assert!(from_json("1e-1000001").is_err());
+        assert_eq!(from_json("1e-70000").unwrap().to_string().len(), 70002);
+    }
+}
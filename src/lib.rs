@@ -0,0 +1,48 @@
+//! An interpreter for hati expressions.
+//!
+//! A [`Machine`] reads an expression, compiles it to reverse polish notation
+//! and runs it. Values are [`Token`]s: numbers, strings, lists and entries,
+//! written back in the notation the language reads. Operators defined with
+//! `name: {...}` stay defined for the following expressions.
+//!
+//! ```
+//! use handrit::{Machine, Token};
+//!
+//! let mut machine = Machine::new();
+//! assert_eq!(machine.run("1 + 2 * 3")?.to_string(), "7");
+//! machine.run("double: {right * 2}")?;
+//! assert_eq!(machine.run("double 21")?.to_string(), "42");
+//! machine.define("name", Token::String("hati".to_string()));
+//! assert_eq!(machine.run("upper name")?.to_string(), "\"HATI\"");
+//! # Ok::<(), handrit::Error>(())
+//! ```
+//!
+//! Errors tell what went wrong and, when they can, where:
+//!
+//! ```
+//! use handrit::{ErrorKind, Machine, Span};
+//!
+//! let error = Machine::new().run("1 + totl").unwrap_err();
+//! assert_eq!(error.kind, ErrorKind::Name);
+//! assert_eq!(error.span, Some(Span::new(4, 8)));
+//! ```
+
+pub mod csv;
+mod debugger;
+pub mod doctest;
+mod error;
+pub mod highlight;
+pub mod json;
+mod limits;
+pub mod lsp;
+mod policy;
+mod rpn;
+
+pub use error::{Error, ErrorKind, Span};
+pub use bigdecimal::BigDecimal;
+pub use debugger::{Breakpoint, Debugger};
+pub use limits::Limits;
+pub use policy::FsPolicy;
+pub use rpn::{
+    Assoc, Machine, OperatorType, Output, Step, StepHook, Token, Tracer, MAX_DISPLAY_ITEMS,
+};
@@ -0,0 +1,230 @@
+use crate::error::Error;
+use crate::rpn::Token;
+
+use bigdecimal::BigDecimal;
+use std::time::{Duration, Instant};
+
+/// Bounds on what running one expression may take, for input that can't be
+/// trusted. `None` leaves a resource unbounded, which is the default for
+/// `steps` and `time` only: deep recursion would overflow the native stack,
+/// and a huge list or number exhaust memory or run for hours, none of which
+/// can be recovered from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// Tokens executed, those of operator bodies and deferred operands included.
+    pub steps: Option<u64>,
+    /// Brace operator calls running at once.
+    pub depth: Option<usize>,
+    /// Digits of a number as written, zeros included.
+    pub digits: Option<u64>,
+    /// Elements of a list or bytes of a string.
+    pub length: Option<usize>,
+    /// Wall-clock time. It is checked between steps, so a single huge
+    /// multiplication may overrun it; `digits` bounds those.
+    pub time: Option<Duration>,
+}
+
+/// The default bound on nested operator calls.
+const DEFAULT_DEPTH: usize = 100;
+
+/// The default bound on the length of a value, a list of that many numbers
+/// taking about a hundred megabytes.
+const DEFAULT_LENGTH: usize = 1_000_000;
+
+/// The default bound on the digits of a number, far more than any formula
+/// needs while multiplying such numbers still takes a moment.
+const DEFAULT_DIGITS: u64 = 100_000;
+
+/// How deep groups in source and lists and entries in values may nest,
+/// whatever the limits: reading, printing or dropping deeper ones recurses
+/// far enough to overflow the native stack.
+pub(crate) const MAX_NESTING: usize = 100;
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            steps: None,
+            depth: Some(DEFAULT_DEPTH),
+            digits: Some(DEFAULT_DIGITS),
+            length: Some(DEFAULT_LENGTH),
+            time: None,
+        }
+    }
+}
+
+/// How often, in steps, the clock is read.
+const CLOCK_INTERVAL: u64 = 1024;
+
+/// The limits of a run and what it has used of them.
+pub(crate) struct Budget {
+    limits: Limits,
+    steps: u64,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: &Limits) -> Budget {
+        Budget {
+            limits: limits.clone(),
+            steps: 0,
+            deadline: limits.time.map(|time| Instant::now() + time),
+        }
+    }
+
+    /// Counts one step, failing once the steps or the time run out.
+    pub(crate) fn step(&mut self) -> Result<(), Error> {
+        self.steps += 1;
+        if let Some(steps) = self.limits.steps.filter(|&steps| self.steps > steps) {
+            return Err(Error::limit(format!("more than {} steps", steps)));
+        }
+        if self.steps.is_multiple_of(CLOCK_INTERVAL) {
+            if let (Some(deadline), Some(time)) = (self.deadline, self.limits.time) {
+                if Instant::now() > deadline {
+                    return Err(Error::limit(format!("ran for more than {:?}", time)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails when `depth` operator calls would be running at once.
+    pub(crate) fn depth(&self, depth: usize) -> Result<(), Error> {
+        match self.limits.depth {
+            Some(limit) if depth > limit => {
+                Err(Error::limit(format!("more than {} nested operator calls", limit)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails for a list or string longer than allowed, before building it.
+    pub(crate) fn length(&self, length: usize) -> Result<(), Error> {
+        match self.limits.length {
+            Some(limit) if length > limit => {
+                Err(Error::limit(format!("a value longer than {}", limit)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn number(&self, num: &BigDecimal) -> Result<(), Error> {
+        match self.limits.digits {
+            Some(limit) if written_digits(num) > limit => {
+                Err(Error::limit(format!("a number of more than {} digits", limit)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the size of a value an operator produced. Only the outer list
+    /// is measured; its elements were checked when they were made.
+    pub(crate) fn check(&self, value: &Token) -> Result<(), Error> {
+        if nested_deeper(value, MAX_NESTING) {
+            return Err(Error::limit(format!("a value nested more than {} deep", MAX_NESTING)));
+        }
+        match value {
+            Token::Number(num) => self.number(num),
+            Token::String(s) => self.length(s.len()),
+            Token::List(items) => self.length(items.len()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether lists and entries nest more than `limit` deep in `value`. It
+/// looks no deeper than that, however deep the value goes.
+fn nested_deeper(value: &Token, limit: usize) -> bool {
+    match value {
+        Token::List(items) => {
+            limit == 0 || items.iter().any(|item| nested_deeper(item, limit - 1))
+        }
+        Token::Entry(key, value) => {
+            limit == 0 || nested_deeper(key, limit - 1) || nested_deeper(value, limit - 1)
+        }
+        _ => false,
+    }
+}
+
+/// The digits `num` is written with: `1000` has four and `0.001` four.
+fn written_digits(num: &BigDecimal) -> u64 {
+    let (_, scale) = num.as_bigint_and_exponent();
+    let digits = num.digits();
+    if scale < 0 {
+        digits + scale.unsigned_abs()
+    } else {
+        digits.max(scale as u64 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{ErrorKind, Span};
+    use crate::rpn::Machine;
+
+    fn limited(limits: Limits) -> Machine {
+        let mut machine = Machine::new();
+        machine.set_limits(limits);
+        machine.run("down: {(true: 0, false: (this (right - 1))) . (right <= 0)}").unwrap();
+        // Twice the calls for each level: long running but never deep.
+        let tree = "tree: {(true: 0, false: ((this (right - 1)) + (this (right - 1)))) \
+            . (right <= 0)}";
+        machine.run(tree).unwrap();
+        machine
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut machine = limited(Limits { steps: Some(1000), ..Limits::default() });
+        let error = machine.run("tree 20").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Limit);
+        assert_eq!(error.message, "more than 1000 steps");
+        assert_eq!(machine.run("1 + 1").unwrap().to_string(), "2");
+
+        let mut machine = limited(Limits { depth: Some(20), ..Limits::default() });
+        let error = machine.run("down 20").unwrap_err();
+        assert_eq!(error.message, "more than 20 nested operator calls");
+        assert_eq!(machine.run("down 19").unwrap().to_string(), "0");
+        assert_eq!(limited(Limits::default()).run("down 1000").unwrap_err().kind, ErrorKind::Limit);
+
+        let mut machine = limited(Limits { length: Some(100), ..Limits::default() });
+        assert_eq!(machine.run("0 .. 1000000000000").unwrap_err().kind, ErrorKind::Limit);
+        assert_eq!(machine.run("\"ab\" * 51").unwrap_err().kind, ErrorKind::Limit);
+        assert_eq!(machine.run("len (0 .. 100)").unwrap().to_string(), "100");
+        let error = Machine::new().run("0 .. 99999999999999").unwrap_err();
+        assert_eq!(error.message, "a value longer than 1000000");
+        let error = Machine::new().run("2 ** 99999999").unwrap_err();
+        assert_eq!(error.message, "a number of more than 100000 digits");
+        assert_eq!(Machine::new().run("(2 ** 300000) > 0").unwrap().to_string(), "1");
+
+        let mut machine = limited(Limits { digits: Some(20), ..Limits::default() });
+        assert_eq!(machine.run("10 ** 20").unwrap_err().kind, ErrorKind::Limit);
+        assert_eq!(machine.run("0.5 ** 20").unwrap_err().kind, ErrorKind::Limit);
+        assert_eq!(machine.run("2 ** 64").unwrap().to_string(), "18446744073709551616");
+
+        let time = Some(Duration::from_millis(50));
+        let mut machine = limited(Limits { time, ..Limits::default() });
+        assert_eq!(machine.run("tree 30").unwrap_err().message, "ran for more than 50ms");
+    }
+
+    #[test]
+    fn test_nesting() {
+        let nested = |open: &str, close: &str, n| format!("{}1{}", open.repeat(n), close.repeat(n));
+        let mut machine = Machine::new();
+        assert!(machine.run(&nested("[", "]", MAX_NESTING)).is_ok());
+        for (open, close) in [("(", ")"), ("[", "]"), ("{", "}"), ("{x: ", "}"), ("[{", "}]")] {
+            let error = machine.run(&nested(open, close, MAX_NESTING + 1)).unwrap_err();
+            assert_eq!(error.message, "groups nested more than 100 deep", "{}", open);
+            assert!(machine.to_rpn(&nested(open, close, 10_000)).is_err());
+        }
+        assert_eq!(machine.run_rpn(&nested("[ ", " ]", 10_000)).unwrap_err().kind, ErrorKind::Lex);
+        let body = format!("{{right + {}}}", nested("{x: ", "}", 10_000));
+        assert_eq!(machine.to_rpn(&body).unwrap_err().span, Some(Span::new(405, 406)));
+
+        // Values nest without groups nesting in the source.
+        machine.run("wrap: {[right]}").unwrap();
+        let error = machine.run(&format!("{}1", "wrap ".repeat(10_000))).unwrap_err();
+        assert_eq!(error.message, "a value nested more than 100 deep");
+        assert!(machine.run(&format!("{}1", "wrap ".repeat(MAX_NESTING))).is_ok());
+    }
+}
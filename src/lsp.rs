@@ -0,0 +1,477 @@
+//! A language server for hati sources, speaking the Language Server Protocol
+//! over a pair of streams. It keeps the text of the open documents and
+//! answers with diagnostics, hovers over operators, completions and the
+//! definitions of names bound with `:`.
+
+use crate::error::{Error, ErrorKind, Span};
+use crate::json::{from_json, to_json};
+use crate::limits::Limits;
+use crate::policy::FsPolicy;
+use crate::rpn::{bindings, Assoc, Machine, OperatorType, Token};
+
+use bigdecimal::{BigDecimal, ToPrimitive};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// What the server answers `initialize` with. Documents are synced whole.
+const CAPABILITIES: &str = concat!(
+    r#"{"capabilities":{"textDocumentSync":1,"hoverProvider":true,"#,
+    r#""completionProvider":{},"definitionProvider":true},"serverInfo":{"name":"handrit"}}"#,
+);
+
+/// Diagnostic severities.
+const ERROR: usize = 1;
+const WARNING: usize = 2;
+
+/// Completion item kinds.
+const VARIABLE: usize = 6;
+const OPERATOR: usize = 24;
+
+/// The dry evaluation of a document stops after this many steps or this long;
+/// a formula running longer isn't an error for that.
+const DRY_STEPS: u64 = 100_000;
+const DRY_TIME: Duration = Duration::from_secs(1);
+
+/// Serves one client until it says `exit` or `input` ends.
+pub fn serve(input: &mut impl BufRead, output: &mut impl Write) -> Result<(), Error> {
+    let mut documents: HashMap<String, String> = HashMap::new();
+    while let Some(message) = read_message(input)? {
+        let Ok(request) = from_json(&message) else {
+            let error = r#"{"code":-32700,"message":"parse error"}"#;
+            send(output, &format!(r#"{{"jsonrpc":"2.0","id":null,"error":{}}}"#, error))?;
+            continue;
+        };
+        let id = field(&request, &["id"]);
+        let uri = text(&request, &["params", "textDocument", "uri"]).unwrap_or_default();
+        let method = text(&request, &["method"]).unwrap_or_default();
+        let result = match method {
+            "initialize" => Some(CAPABILITIES.to_string()),
+            "shutdown" => None,
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                let source = text(&request, &["params", "textDocument", "text"]);
+                documents.insert(uri.to_string(), source.unwrap_or_default().to_string());
+                publish(output, uri, &documents[uri])?;
+                continue;
+            }
+            "textDocument/didChange" => {
+                // The whole text, in the last change as the sync is full.
+                let source = match field(&request, &["params", "contentChanges"]) {
+                    Some(Token::List(changes)) => {
+                        changes.last().and_then(|last| text(last, &["text"]))
+                    }
+                    _ => None,
+                };
+                if let Some(source) = source {
+                    documents.insert(uri.to_string(), source.to_string());
+                    publish(output, uri, source)?;
+                }
+                continue;
+            }
+            "textDocument/didClose" => {
+                documents.remove(uri);
+                publish(output, uri, "")?;
+                continue;
+            }
+            "textDocument/hover" | "textDocument/completion" | "textDocument/definition" => {
+                let source = documents.get(uri).map_or("", String::as_str);
+                let line = count(&request, &["params", "position", "line"]).unwrap_or(0);
+                let character = count(&request, &["params", "position", "character"]).unwrap_or(0);
+                let offset = offset(source, line, character);
+                let answer = match method {
+                    "textDocument/hover" => hover(source, offset),
+                    "textDocument/completion" => Some(completions(source)),
+                    _ => definition(source, offset, uri),
+                };
+                answer.map(|answer| to_json(&answer))
+            }
+            _ if id.is_some() => {
+                let error = object(vec![
+                    ("code", Token::Number(BigDecimal::from(-32601))),
+                    ("message", string(&format!("unknown method {}", method))),
+                ]);
+                let id = id.map_or("null".to_string(), to_json);
+                let error = to_json(&error);
+                send(output, &format!(r#"{{"jsonrpc":"2.0","id":{},"error":{}}}"#, id, error))?;
+                continue;
+            }
+            _ => continue,
+        };
+        let id = id.map_or("null".to_string(), to_json);
+        let result = result.unwrap_or("null".to_string());
+        send(output, &format!(r#"{{"jsonrpc":"2.0","id":{},"result":{}}}"#, id, result))?;
+    }
+    Ok(())
+}
+
+/// Reads the body of the next message, `None` once `input` ends.
+fn read_message(input: &mut impl BufRead) -> Result<Option<String>, Error> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).map_err(|e| Error::io(e.to_string()))? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| Error::data("message without a Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| Error::io(e.to_string()))?;
+    String::from_utf8(body).map(Some).map_err(|_| Error::data("message is not UTF-8"))
+}
+
+fn send(output: &mut impl Write, body: &str) -> Result<(), Error> {
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| output.flush())
+        .map_err(|e| Error::io(e.to_string()))
+}
+
+/// Sends the diagnostics of a document, replacing those sent before.
+fn publish(output: &mut impl Write, uri: &str, source: &str) -> Result<(), Error> {
+    let params = object(vec![
+        ("uri", string(uri)),
+        ("diagnostics", Token::List(diagnostics(source))),
+    ]);
+    let message = format!(
+        r#"{{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{}}}"#,
+        to_json(&params)
+    );
+    send(output, &message)
+}
+
+/// What is wrong with `source`: an error reading it, or else one running it
+/// with no file access. Unless it says where, a run error is shown at the
+/// start; reading files and running out of time aren't reported.
+fn diagnostics(source: &str) -> Vec<Token> {
+    if let Err(error) = Machine::new().check(source) {
+        return vec![diagnostic(source, &error, ERROR)];
+    }
+    if source.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut machine = Machine::new();
+    machine.set_fs_policy(FsPolicy::Deny);
+    // Standard output carries the protocol; what the document prints goes nowhere.
+    machine.set_output(Rc::new(RefCell::new(io::sink())));
+    let limits = Limits { steps: Some(DRY_STEPS), time: Some(DRY_TIME), ..Limits::default() };
+    machine.set_limits(limits);
+    match machine.run(source) {
+        Err(error) if !matches!(error.kind, ErrorKind::Permission | ErrorKind::Limit) => {
+            vec![diagnostic(source, &error, WARNING)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn diagnostic(source: &str, error: &Error, severity: usize) -> Token {
+    object(vec![
+        ("range", range(source, error.span.unwrap_or(Span::new(0, 0)))),
+        ("severity", number(severity)),
+        ("source", string("handrit")),
+        ("message", string(&format!("{} error: {}", error.kind, error.message))),
+    ])
+}
+
+/// The fixity, precedence, associativity and operands of the operators the
+/// word under the cursor names, those the document defines included.
+fn hover(source: &str, offset: usize) -> Option<Token> {
+    let (word, span) = word_at(source, offset)?;
+    let mut machine = Machine::new();
+    let _ = machine.check(source);
+    let lines: Vec<String> = machine
+        .operators()
+        .iter()
+        .filter(|operator| operator.symbol == word)
+        .map(|operator| {
+            let (fixity, operands) = match operator.op_type {
+                OperatorType::Prefix => ("prefix", "right"),
+                OperatorType::Infix => ("infix", "left and right"),
+                OperatorType::Postfix => ("postfix", "left"),
+            };
+            let assoc = match operator.assoc {
+                Assoc::Left => "left",
+                Assoc::Right => "right",
+            };
+            format!(
+                "`{}` {}: precedence {}, {} associative, takes {}",
+                word, fixity, operator.precedence, assoc, operands
+            )
+        })
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+    let value = string(&lines.join("\n\n"));
+    let contents = object(vec![("kind", string("markdown")), ("value", value)]);
+    Some(object(vec![("contents", contents), ("range", range(source, span))]))
+}
+
+/// The operators, their fixities as detail, then the names the document
+/// binds, each once and sorted.
+fn completions(source: &str) -> Token {
+    let mut machine = Machine::new();
+    let _ = machine.check(source);
+    let mut operators: Vec<(&str, Vec<&str>)> = Vec::new();
+    for operator in machine.operators() {
+        // A brace's own symbol is its body; a name bound to it comes apart.
+        if operator.symbol.starts_with('{') {
+            continue;
+        }
+        let fixity = match operator.op_type {
+            OperatorType::Prefix => "prefix",
+            OperatorType::Infix => "infix",
+            OperatorType::Postfix => "postfix",
+        };
+        match operators.iter_mut().find(|(symbol, _)| *symbol == operator.symbol) {
+            Some((_, fixities)) => fixities.push(fixity),
+            None => operators.push((&operator.symbol, vec![fixity])),
+        }
+    }
+    for (_, fixities) in &mut operators {
+        fixities.sort();
+    }
+    operators.sort();
+    let mut names: Vec<String> = bindings(source).into_iter().map(|(name, _)| name).collect();
+    names.sort();
+    names.dedup();
+    names.retain(|name| !operators.iter().any(|(symbol, _)| symbol == name));
+    let item = |label: &str, kind: usize, detail: String| {
+        object(vec![("label", string(label)), ("kind", number(kind)), ("detail", string(&detail))])
+    };
+    let operators = operators.iter().map(|(symbol, fixities)| {
+        item(symbol, OPERATOR, format!("{} operator", fixities.join(", ")))
+    });
+    let names = names.iter().map(|name| item(name, VARIABLE, "variable".to_string()));
+    Token::List(operators.chain(names).collect())
+}
+
+/// Where the name under the cursor is bound: the last binding before it, or
+/// the first one when it is used before being bound.
+fn definition(source: &str, offset: usize, uri: &str) -> Option<Token> {
+    let (word, _) = word_at(source, offset)?;
+    let bound: Vec<Span> = bindings(source)
+        .into_iter()
+        .filter(|(name, _)| name == word)
+        .map(|(_, span)| span)
+        .collect();
+    let span = bound
+        .iter()
+        .rev()
+        .find(|span| span.start <= offset)
+        .or_else(|| bound.first())?;
+    Some(object(vec![("uri", string(uri)), ("range", range(source, *span))]))
+}
+
+/// The name or operator symbol at `offset`, or just before it, and where
+/// it is written.
+fn word_at(source: &str, offset: usize) -> Option<(&str, Span)> {
+    let class = |ch: char| {
+        if ch.is_alphanumeric() || ch == '_' {
+            Some(true)
+        } else if ch.is_whitespace() || "()[]{}\"'".contains(ch) {
+            None
+        } else {
+            Some(false)
+        }
+    };
+    let after = source[offset..].chars().next().and_then(class);
+    let before = source[..offset].chars().next_back().and_then(class);
+    let word_class = after.or(before)?;
+    let same = |ch: char| class(ch) == Some(word_class);
+    let start = source[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, ch)| same(ch))
+        .last()
+        .map_or(offset, |(pos, _)| pos);
+    let end = source[offset..]
+        .char_indices()
+        .find(|&(_, ch)| !same(ch))
+        .map_or(source.len(), |(pos, _)| offset + pos);
+    Some((&source[start..end], Span::new(start, end)))
+}
+
+/// The byte offset of an LSP position: a line from 0 and a column counted in
+/// UTF-16 code units. Positions past the end of a line are at its end.
+fn offset(source: &str, line: usize, character: usize) -> usize {
+    let start: usize = source.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (pos, ch) in source[start..].char_indices() {
+        if ch == '\n' || units >= character {
+            return start + pos;
+        }
+        units += ch.len_utf16();
+    }
+    source.len()
+}
+
+/// The LSP position of the byte `offset`.
+fn position(source: &str, offset: usize) -> Token {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+    object(vec![
+        ("line", number(before.matches('\n').count())),
+        ("character", number(before[line_start..].encode_utf16().count())),
+    ])
+}
+
+fn range(source: &str, span: Span) -> Token {
+    object(vec![("start", position(source, span.start)), ("end", position(source, span.end))])
+}
+
+/// A JSON object, as `to_json` writes a list of entries.
+fn object(fields: Vec<(&str, Token)>) -> Token {
+    let entries = fields
+        .into_iter()
+        .map(|(key, value)| Token::Entry(Box::new(string(key)), Box::new(value)));
+    Token::List(entries.collect())
+}
+
+fn string(s: &str) -> Token {
+    Token::String(s.to_string())
+}
+
+fn number(n: usize) -> Token {
+    Token::Number(BigDecimal::from(n as u64))
+}
+
+/// The value at `path` in nested JSON objects.
+fn field<'a>(token: &'a Token, path: &[&str]) -> Option<&'a Token> {
+    path.iter().try_fold(token, |token, key| match token {
+        Token::List(entries) => entries.iter().find_map(|entry| match entry {
+            Token::Entry(name, value) if matches!(&**name, Token::String(name) if name == key) => {
+                Some(&**value)
+            }
+            _ => None,
+        }),
+        _ => None,
+    })
+}
+
+fn text<'a>(token: &'a Token, path: &[&str]) -> Option<&'a str> {
+    match field(token, path)? {
+        Token::String(s) => Some(s),
+        _ => None,
+    }
+}
+
+fn count(token: &Token, path: &[&str]) -> Option<usize> {
+    match field(token, path)? {
+        Token::Number(num) => num.to_usize(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames `messages` as a client would and returns the bodies the server
+    /// sends back.
+    fn session(messages: &[&str]) -> Vec<String> {
+        let mut input = Vec::new();
+        for message in messages {
+            write!(input, "Content-Length: {}\r\n\r\n{}", message.len(), message).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(&mut input.as_slice(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|message| message.split_once("\r\n\r\n").unwrap().1.to_string())
+            .collect()
+    }
+
+    fn open(source: &str) -> String {
+        let document = object(vec![("uri", string("file:///a.hati")), ("text", string(source))]);
+        let params = object(vec![("textDocument", document)]);
+        let method = "textDocument/didOpen";
+        format!(r#"{{"jsonrpc":"2.0","method":"{}","params":{}}}"#, method, to_json(&params))
+    }
+
+    fn at(id: usize, method: &str, line: usize, character: usize) -> String {
+        let document = object(vec![("uri", string("file:///a.hati"))]);
+        let position = object(vec![("line", number(line)), ("character", number(character))]);
+        let params = object(vec![("textDocument", document), ("position", position)]);
+        format!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"textDocument/{}","params":{}}}"#,
+            id,
+            method,
+            to_json(&params)
+        )
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let sources = ["1 + (2", "[(total: 3)\n (1 + totl)]", "@ \"x\""];
+        let opened = sources.map(open);
+        let replies = session(&opened.each_ref().map(String::as_str));
+        assert!(replies[0].contains(r#""message":"lex error: unclosed (""#), "{}", replies[0]);
+        assert!(replies[0].contains(r#""start":{"line":0,"character":4}"#));
+        assert!(replies[1].contains(r#""severity":2"#), "{}", replies[1]);
+        let range = r#""start":{"line":1,"character":6},"end":{"line":1,"character":10}"#;
+        assert!(replies[1].contains(range));
+        assert!(replies[2].contains(r#""diagnostics":[]"#), "{}", replies[2]);
+    }
+
+    #[test]
+    fn test_requests() {
+        let source = "[(inc: {right + 1})\n(x: 2) (inc x) (x * 2)]";
+        let replies = session(&[
+            r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{}}"#,
+            &open(source),
+            &at(1, "hover", 1, 9),
+            &at(2, "hover", 1, 18),
+            &at(3, "definition", 1, 17),
+            &at(4, "completion", 1, 0),
+            &at(5, "hover", 1, 1),
+            r#"{"jsonrpc":"2.0","id":6,"method":"workspace/symbol","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+            &at(7, "hover", 0, 0),
+        ]);
+        assert_eq!(replies.len(), 8);
+        assert!(replies[0].contains(r#""hoverProvider":true"#));
+        assert!(replies[1].contains(r#""diagnostics":[]"#), "{}", replies[1]);
+        let expected = "`inc` prefix: precedence 20, left associative, takes right";
+        assert!(replies[2].contains(expected), "{}", replies[2]);
+        assert!(replies[3].contains("`*` infix: precedence"), "{}", replies[3]);
+        let definition = r#""start":{"line":1,"character":1},"end":{"line":1,"character":2}"#;
+        assert!(replies[4].contains(definition), "{}", replies[4]);
+        assert!(replies[5].contains(r#"{"label":"x","kind":6,"detail":"variable"}"#));
+        assert!(replies[5].contains(r#"{"label":"inc","kind":24,"detail":"prefix operator"}"#));
+        let minus = r#"{"label":"-","kind":24,"detail":"infix, prefix operator"}"#;
+        assert!(replies[5].contains(minus), "{}", replies[5]);
+        assert_eq!(replies[6], r#"{"jsonrpc":"2.0","id":5,"result":null}"#);
+        assert!(replies[7].contains(r#""code":-32601"#));
+    }
+
+    #[test]
+    fn test_positions() {
+        let source = "a\n\u{1F600}é b\n";
+        assert_eq!(offset(source, 1, 0), 2);
+        assert_eq!(offset(source, 1, 3), 8);
+        assert_eq!(offset(source, 1, 99), 10);
+        assert_eq!(offset(source, 9, 0), source.len());
+        assert_eq!(to_json(&position(source, 9)), r#"{"line":1,"character":4}"#);
+        assert_eq!(word_at(source, 9), Some(("b", Span::new(9, 10))));
+        assert_eq!(word_at(source, 10), Some(("b", Span::new(9, 10))));
+        assert_eq!(word_at("1 <= 2", 3), Some(("<=", Span::new(2, 4))));
+        assert_eq!(word_at("( )", 1), None);
+    }
+}
@@ -1,9 +1,15 @@
-mod rpn;
-use rpn::{Machine, Token};
+use handrit::{
+    csv, doctest, highlight, json, Breakpoint, Debugger, Error, FsPolicy, Machine, Span, Step,
+    Token, Tracer,
+};
 
-use clap::{Arg, Command, Subcommand};
+use bigdecimal::BigDecimal;
+
+use clap::{Arg, ArgAction, Command, Subcommand};
 use std::fs::File;
 use std::io::{self, Read, Write}; // Import Write trait for flush
+use std::path::PathBuf;
+use std::rc::Rc;
 
 #[derive(Subcommand)]
 enum Commands {
@@ -34,17 +40,145 @@ fn main() {
                         .value_name("STRING or FILE")
                         .help("The expression string or path to a file containing the expression.")
                         .required(true),
+                )
+                .arg(
+                    Arg::new("eval")
+                        .short('e')
+                        .long("eval")
+                        .help("Execute the input as an RPN program instead of converting it.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("trace")
+                        .long("trace")
+                        .value_name("FORMAT")
+                        .help("Prints each step and the stack after it to stderr, as text or JSON.")
+                        .value_parser(["text", "json"])
+                        .num_args(0..=1)
+                        .default_missing_value("text"),
                 ),
         )
         .subcommand(
-            Command::new("run").about("Evaluates expression").arg(
-                Arg::new("input")
-                    .short('i')
-                    .long("input")
-                    .value_name("STRING or FILE")
-                    .help("The expression string or path to a file containing the expression.")
-                    .required(true),
-            ),
+            Command::new("debug")
+                .about("Evaluates an expression step by step, pausing at breakpoints.")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("STRING or FILE")
+                        .help("The expression string or path to a file containing the expression.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("break")
+                        .short('b')
+                        .long("break")
+                        .value_name("NAME or LINE")
+                        .help("Pauses on entering the operator NAME or one defined on LINE.")
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("highlight")
+                .about("Prints an expression colored by what the tokenizer reads in it.")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("STRING or FILE")
+                        .help("The expression string or path to a file containing the expression.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("ANSI escapes for a terminal or HTML spans to style with CSS.")
+                        .value_parser(["ansi", "html"])
+                        .default_value("ansi"),
+                ),
+        )
+        .subcommand(
+            Command::new("lsp")
+                .about("Serves editors the Language Server Protocol over stdin and stdout."),
+        )
+        .subcommand(
+            Command::new("test")
+                .about("Checks the `> value` examples in .hati and .org files.")
+                .arg(
+                    Arg::new("paths")
+                        .value_name("FILE or DIR")
+                        .help("Files to check, or directories to search for them.")
+                        .num_args(1..)
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::new("filter")
+                        .short('f')
+                        .long("filter")
+                        .value_name("TEXT")
+                        .help("Runs only the tests whose name holds TEXT, no examples."),
+                )
+                .arg(
+                    Arg::new("list")
+                        .short('l')
+                        .long("list")
+                        .help("Lists the tests instead of running them.")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Evaluates expression")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("STRING or FILE")
+                        .help("The expression string or path to a file containing the expression.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FORMAT")
+                        .help("How to print the result or the error.")
+                        .value_parser(["text", "hati", "json", "csv"])
+                        .default_value("text"),
+                )
+                .arg(
+                    Arg::new("data")
+                        .short('d')
+                        .long("data")
+                        .value_name("NAME=FILE")
+                        .help("Binds NAME to FILE, parsing .json and .csv files.")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("allow-read")
+                        .long("allow-read")
+                        .value_name("DIR")
+                        .help("Only lets files under DIR be read, and none be written.")
+                        .action(ArgAction::Append)
+                        .conflicts_with("deny-fs"),
+                )
+                .arg(
+                    Arg::new("deny-fs")
+                        .long("deny-fs")
+                        .help("Lets no file be read or written, the input included.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("trace")
+                        .long("trace")
+                        .value_name("FORMAT")
+                        .help("Prints each step and the stack after it to stderr, as text or JSON.")
+                        .value_parser(["text", "json"])
+                        .num_args(0..=1)
+                        .default_missing_value("text"),
+                ),
         )
         .get_matches();
 
@@ -59,53 +193,287 @@ fn main() {
         }
         Some(("rpn", sub_m)) => {
             let input = sub_m.get_one::<String>("input").unwrap();
-            match read_input(input) {
-                Ok(content) => println!("{}", machine.to_rpn(&content)),
+            if let Some(format) = sub_m.get_one::<String>("trace") {
+                machine.set_tracer(Some(tracer(format)));
+            }
+            match read_input(input, &FsPolicy::All) {
+                Ok(content) if sub_m.get_flag("eval") => {
+                    if !print_result(machine.run_rpn(&content), "text") {
+                        std::process::exit(1);
+                    }
+                }
+                Ok(content) => match machine.to_rpn(&content) {
+                    Ok(rpn) => println!("{}", rpn),
+                    Err(e) => eprintln!("{}", e),
+                },
                 Err(e) => eprintln!("Error reading input: {}", e),
             }
         }
+        Some(("debug", sub_m)) => {
+            let input = sub_m.get_one::<String>("input").unwrap();
+            let debugger = stdin_debugger();
+            for breakpoint in sub_m.get_many::<String>("break").unwrap_or_default() {
+                debugger.add_breakpoint(Breakpoint::parse(breakpoint));
+            }
+            debugger.attach(&mut machine);
+            let result = read_input(input, &FsPolicy::All).and_then(|content| {
+                debugger.start(&content);
+                machine.run(&content)
+            });
+            if !print_result(result, "text") {
+                std::process::exit(1);
+            }
+        }
+        Some(("highlight", sub_m)) => {
+            let input = sub_m.get_one::<String>("input").unwrap();
+            let format = sub_m.get_one::<String>("format").unwrap();
+            let result = read_input(input, &FsPolicy::All).and_then(|content| {
+                let lexemes = machine.lexemes(&content)?;
+                Ok(match format.as_str() {
+                    "html" => highlight::to_html(&content, &lexemes),
+                    _ => highlight::to_ansi(&content, &lexemes),
+                })
+            });
+            match result {
+                Ok(text) => print!("{}", text),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("test", sub_m)) => {
+            let mut files = Vec::new();
+            for path in sub_m.get_many::<String>("paths").unwrap() {
+                doctest::find_tests(&PathBuf::from(path), true, &mut files);
+            }
+            let filter = sub_m.get_one::<String>("filter").map(String::as_str);
+            let list = sub_m.get_flag("list");
+            let (mut passed, mut failed) = (0, 0);
+            for file in &files {
+                let result = doctest::test_file(file, filter, list, &mut io::stdout().lock());
+                let (file_passed, file_failed) = result.unwrap_or((0, 1));
+                passed += file_passed;
+                failed += file_failed;
+            }
+            if !list {
+                println!("{} files, {} passed, {} failed", files.len(), passed, failed);
+            }
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Some(("lsp", _)) => {
+            let result = handrit::lsp::serve(&mut io::stdin().lock(), &mut io::stdout().lock());
+            if let Err(error) = result {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
         Some(("run", sub_m)) => {
             let input = sub_m.get_one::<String>("input").unwrap();
-            match read_input(input) {
-                Ok(content) => print_result(machine.run(&content)),
-                Err(e) => eprintln!("Error reading input: {}", e),
+            let output = sub_m.get_one::<String>("output").unwrap();
+            let policy = if sub_m.get_flag("deny-fs") {
+                FsPolicy::Deny
+            } else if let Some(dirs) = sub_m.get_many::<String>("allow-read") {
+                FsPolicy::ReadOnly(dirs.map(PathBuf::from).collect())
+            } else {
+                FsPolicy::All
+            };
+            for data in sub_m.get_many::<String>("data").unwrap_or_default() {
+                if let Err(e) = load_data(&mut machine, data, &policy) {
+                    print_result(Err(e), output);
+                    std::process::exit(1);
+                }
+            }
+            machine.set_fs_policy(policy.clone());
+            if let Some(format) = sub_m.get_one::<String>("trace") {
+                machine.set_tracer(Some(tracer(format)));
+            }
+            match read_input(input, &policy) {
+                Ok(content) => {
+                    if !print_result(machine.run(&content), output) {
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    print_result(Err(e), output);
+                    std::process::exit(1);
+                }
             }
         }
         _ => unreachable!(),
     }
 }
-fn print_result(result: Result<Token, String>) {
-    match result.unwrap() {
-        Token::Number(num) => println!("{} ", num),
-        Token::String(s) => println!("\"{}\" ", s),
-        Token::Variable(var) => println!("{} ", var),
-        Token::Operator(op, _) => println!("{} ", op),
-        _ => {}
+
+/// Prints a result in the given output format and tells whether it succeeded.
+/// `text` is meant for people, so errors go to stderr; `hati` and `json` print
+/// errors as `{error: {kind, message, span}}` values on stdout.
+fn print_result(result: Result<Token, Error>, output: &str) -> bool {
+    let ok = result.is_ok();
+    match (output, result) {
+        ("hati", Ok(value)) => println!("{:#}", value),
+        ("hati", Err(e)) => println!("{:#}", error_value(&e)),
+        ("json", Ok(value)) => println!("{{\"value\":{}}}", json::to_json(&value)),
+        ("json", Err(e)) => println!("{}", json::to_json(&error_value(&e))),
+        ("csv", Ok(value)) => match csv::to_csv(&value) {
+            Ok(text) => print!("{}", text),
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        },
+        (_, Ok(value)) => println!("{}", value),
+        (_, Err(e)) => eprintln!("{}", e),
     }
+    ok
+}
+
+/// Reads a `NAME=FILE` binding given with `--data`.
+fn load_data(machine: &mut Machine, binding: &str, policy: &FsPolicy) -> Result<(), Error> {
+    let (name, path) = binding
+        .split_once('=')
+        .ok_or_else(|| Error::io(format!("expected NAME=FILE, got {}", binding)))?;
+    let text = std::fs::read_to_string(policy.check_read(path)?)
+        .map_err(|e| Error::io(format!("cannot read {}: {}", path, e)))?;
+    let value = match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("json") => json::from_json(&text)?,
+        Some("csv") => csv::from_csv(&text)?,
+        _ => Token::String(text),
+    };
+    machine.define(name, value);
+    Ok(())
+}
+
+fn entry(key: &str, value: Token) -> Token {
+    Token::Entry(Box::new(Token::String(key.to_string())), Box::new(value))
+}
+
+/// Prints the steps of a run to stderr: the token and the stack after it,
+/// indented by call depth, or one JSON object per line.
+fn tracer(format: &str) -> Tracer {
+    if format == "json" {
+        Rc::new(|step: &Step| {
+            let stack = step.stack.iter().map(|token| Token::String(format!("{:#}", token)));
+            let line = Token::List(vec![
+                entry("depth", Token::Number(BigDecimal::from(step.depth as u64))),
+                entry("token", Token::String(format!("{:#}", step.token))),
+                entry("stack", Token::List(stack.collect())),
+            ]);
+            eprintln!("{}", json::to_json(&line));
+        })
+    } else {
+        Rc::new(|step: &Step| {
+            let stack: Vec<String> = step.stack.iter().map(|token| token.to_string()).collect();
+            eprintln!("{}{} | {}", "  ".repeat(step.depth), step.token, stack.join(" "));
+        })
+    }
+}
+
+fn error_value(error: &Error) -> Token {
+    let position = |offset: usize| Token::Number(BigDecimal::from(offset as u64));
+    let mut fields = vec![
+        entry("kind", Token::String(error.kind.to_string())),
+        entry("message", Token::String(error.message.clone())),
+    ];
+    if let Some(span) = error.span {
+        fields.push(entry(
+            "span",
+            Token::List(vec![
+                entry("start", position(span.start)),
+                entry("end", position(span.end)),
+            ]),
+        ));
+    }
+    Token::List(vec![entry("error", Token::List(fields))])
+}
+
+/// A debugger reading its commands from stdin, after a prompt on stderr.
+fn stdin_debugger() -> Rc<Debugger> {
+    Debugger::new(
+        || {
+            eprint!("(debug) ");
+            io::stderr().flush().ok();
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(line),
+            }
+        },
+        |line| eprintln!("{}", line),
+    )
 }
 
 fn repl_mode(mut machine: Machine) {
-    println!("Enter REPL mode. Type 'exit' to leave.");
+    println!(
+        "Enter REPL mode. Type 'trace' to show every step, 'debug' to pause at each, \
+        'exit' to leave."
+    );
     let mut input = String::new();
+    let mut tracing = false;
+    let debugger = stdin_debugger();
+    let mut debugging = false;
     loop {
         input.clear();
         print!("> ");
         io::stdout().flush().unwrap(); // Flush stdout to ensure prompt is printed
-        io::stdin().read_line(&mut input).unwrap();
+        if let Ok(0) | Err(_) = io::stdin().read_line(&mut input) {
+            break;
+        }
         let trimmed = input.trim();
         if trimmed == "exit" {
             break;
         }
+        if trimmed.is_empty() {
+            continue;
+        }
+        if trimmed == "trace" {
+            tracing = !tracing;
+            machine.set_tracer(tracing.then(|| tracer("text")));
+            println!("trace {}", if tracing { "on" } else { "off" });
+            continue;
+        }
+        if trimmed == "debug" {
+            debugging = !debugging;
+            if debugging {
+                debugger.attach(&mut machine);
+            } else {
+                machine.set_debugger(None);
+            }
+            println!("debug {}", if debugging { "on" } else { "off" });
+            continue;
+        }
+        if debugging {
+            debugger.start(trimmed);
+        }
         let result = machine.run(trimmed);
-        print_result(result);
+        if let Err(Error { span: Some(span), .. }) = &result {
+            // Under the input, after the prompt and the blanks trimmed off it.
+            let indent = 2 + input.len() - input.trim_start().len();
+            eprintln!("{}", underline(trimmed, *span, indent));
+        }
+        print_result(result, "text");
     }
 }
 
-fn read_input(input: &str) -> io::Result<String> {
-    if std::path::Path::new(input).exists() {
-        let mut file = File::open(input)?;
+/// Marks the part of `line` a span covers with carets.
+fn underline(line: &str, span: Span, indent: usize) -> String {
+    let width = |text: &str| text.chars().count();
+    let start = line.get(..span.start).map_or(0, width);
+    let length = line.get(span.start..span.end).map_or(1, width).max(1);
+    format!("{}{}", " ".repeat(indent + start), "^".repeat(length))
+}
+
+/// The expression itself, or the content of the file it names if the policy
+/// lets that file be read. Whether a file the policy denies exists isn't
+/// looked at, so it reads as an expression either way.
+fn read_input(input: &str, policy: &FsPolicy) -> Result<String, Error> {
+    let path = policy.check_read(input).ok().filter(|path| path.exists());
+    if let Some(path) = path {
+        let io_error = |e: io::Error| Error::io(format!("cannot read {}: {}", input, e));
+        let mut file = File::open(path).map_err(io_error)?;
         let mut content = String::new();
-        file.read_to_string(&mut content)?;
+        file.read_to_string(&mut content).map_err(io_error)?;
         Ok(content)
     } else {
         Ok(input.to_string())
@@ -0,0 +1,91 @@
+use crate::error::Error;
+
+use std::path::{Path, PathBuf};
+
+/// Which files a machine may touch: `@` and `--data` read them, `>>` writes
+/// them. Paths are resolved, `..` and symbolic links included, before they
+/// are checked against the allowed directories.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FsPolicy {
+    /// No file may be read or written.
+    Deny,
+    /// Files under these directories may be read, none written.
+    ReadOnly(Vec<PathBuf>),
+    /// Any file may be read or written, as the user running the machine.
+    #[default]
+    All,
+}
+
+impl FsPolicy {
+    /// Checks that `path` may be read and returns it resolved.
+    pub fn check_read(&self, path: &str) -> Result<PathBuf, Error> {
+        match self {
+            FsPolicy::All => Ok(PathBuf::from(path)),
+            FsPolicy::Deny => Err(denied("read", path)),
+            FsPolicy::ReadOnly(dirs) => {
+                // A path that doesn't resolve can't be checked; saying so
+                // would tell whether it exists outside the directories.
+                let resolved = Path::new(path)
+                    .canonicalize()
+                    .map_err(|_| denied("read", path))?;
+                let allowed = dirs.iter().any(|dir| {
+                    dir.canonicalize().is_ok_and(|dir| resolved.starts_with(dir))
+                });
+                if allowed {
+                    Ok(resolved)
+                } else {
+                    Err(denied("read", path))
+                }
+            }
+        }
+    }
+
+    /// Checks that `path` may be written.
+    pub fn check_write(&self, path: &str) -> Result<(), Error> {
+        match self {
+            FsPolicy::All => Ok(()),
+            FsPolicy::Deny | FsPolicy::ReadOnly(_) => Err(denied("write", path)),
+        }
+    }
+}
+
+fn denied(action: &str, path: &str) -> Error {
+    Error::permission(format!("not allowed to {} {}", action, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::rpn::Machine;
+
+    #[test]
+    fn test_fs_policy() {
+        let dir = std::env::temp_dir().join(format!("handrit-policy-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(dir.join("data").join("in.txt"), "hi").unwrap();
+        std::fs::write(dir.join("secret.txt"), "no").unwrap();
+        let inside = dir.join("data").join("in.txt");
+        let outside = dir.join("data").join("..").join("secret.txt");
+        let read = |machine: &mut Machine, path: &Path| {
+            machine.run(&format!("@ {}", crate::rpn::quote(path.to_str().unwrap())))
+        };
+
+        let mut machine = Machine::new();
+        machine.set_fs_policy(FsPolicy::ReadOnly(vec![dir.join("data")]));
+        assert_eq!(read(&mut machine, &inside).unwrap().to_string(), "\"hi\"");
+        let error = read(&mut machine, &outside).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Permission);
+        let missing = dir.join("missing.txt");
+        assert_eq!(read(&mut machine, &missing).unwrap_err().kind, ErrorKind::Permission);
+        let target = crate::rpn::quote(dir.join("data").join("out.txt").to_str().unwrap());
+        let error = machine.run(&format!("1 >> {}", target)).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Permission);
+
+        machine.set_fs_policy(FsPolicy::Deny);
+        assert_eq!(read(&mut machine, &inside).unwrap_err().kind, ErrorKind::Permission);
+        machine.set_fs_policy(FsPolicy::All);
+        assert_eq!(read(&mut machine, &outside).unwrap().to_string(), "\"no\"");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,31 +1,177 @@
+use bigdecimal::num_bigint::Sign;
 use bigdecimal::BigDecimal;
 use bigdecimal::FromPrimitive;
 use bigdecimal::One;
 use bigdecimal::ToPrimitive;
 use bigdecimal::Zero;
+use crate::csv;
+use crate::error::{Error, Span};
+use crate::highlight::Class;
+use crate::json;
+use crate::limits::{Budget, Limits, MAX_NESTING};
+use crate::policy::FsPolicy;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::convert::From;
-use std::ops::Neg;
+use std::fmt;
+use std::io::{self, Write};
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::CharIndices;
 use std::str::FromStr;
 
+/// A value, or a piece of an expression being compiled or run. Variants
+/// may be added, so matches outside this crate need a wildcard arm.
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Token {
     Number(BigDecimal),
     String(String),
-    Variable(String),
+    /// A name and where it was read, to point at it when it's unbound.
+    Variable(String, Span),
     Operator(String, OperatorType),
     List(Vec<Token>),
+    Entry(Box<Token>, Box<Token>),
+    /// A brace operator used as a value, named by its symbol, with the
+    /// variables it captured where it was evaluated. Copies share them, so
+    /// braces capturing braces stay as small as the braces they capture.
+    Function(String, Rc<[(String, Token)]>),
+    /// Postfix code whose value is computed only when needed.
+    Thunk(Vec<Token>),
     LeftParen,
     RightParen,
     LeftBracket,
     RightBracket,
+    /// Never produced: a brace is read whole, as the operator it defines.
+    #[deprecated(note = "braces are read as operators, never as a LeftBrace")]
     LeftBrace,
+    /// Never produced, like [`Token::LeftBrace`].
+    #[deprecated(note = "braces are read as operators, never as a RightBrace")]
     RightBrace,
     LeftRef,
     RightRef,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Lists longer than this are cut short when displayed, unless the alternate
+/// flag (`{:#}`) asks for every element.
+pub const MAX_DISPLAY_ITEMS: usize = 100;
+
+/// Writes values in the notation the tokenizer reads: `[1 2 3]` for lists,
+/// `{a: 3, 0: 2}` for lists holding entries, quoted strings with escapes.
+/// Booleans are the numbers 1 and 0.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(num) => write!(f, "{}", plain_number(num)),
+            Token::String(s) => write!(f, "{}", quote(s)),
+            Token::Variable(var, _) => write!(f, "{}", var),
+            Token::Operator(op, _) => write!(f, "{}", op),
+            Token::List(list) if list.iter().any(|item| matches!(item, Token::Entry(_, _))) => {
+                write!(f, "{{")?;
+                let mut next_index = 0;
+                for (i, item) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if i == MAX_DISPLAY_ITEMS && !f.alternate() {
+                        return write!(f, "... ({} more)}}", list.len() - i);
+                    }
+                    match item {
+                        Token::Entry(key, value) => {
+                            write_key(f, key)?;
+                            write!(f, ": ")?;
+                            write_nested(f, value)?;
+                        }
+                        value => {
+                            write!(f, "{}: ", next_index)?;
+                            write_nested(f, value)?;
+                            next_index += 1;
+                        }
+                    }
+                }
+                write!(f, "}}")
+            }
+            Token::List(list) => {
+                write!(f, "[")?;
+                for (i, item) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    if i == MAX_DISPLAY_ITEMS && !f.alternate() {
+                        return write!(f, "... ({} more)]", list.len() - i);
+                    }
+                    write_nested(f, item)?;
+                }
+                write!(f, "]")
+            }
+            Token::Entry(key, value) => {
+                write!(f, "{}: ", key)?;
+                write_nested(f, value)
+            }
+            Token::Function(symbol, _) => write!(f, "{}", symbol),
+            Token::Thunk(body) => {
+                write!(f, "(")?;
+                for token in body {
+                    write!(f, " {:#}", token)?;
+                }
+                write!(f, " )")
+            }
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::LeftBracket => write!(f, "["),
+            Token::RightBracket => write!(f, "]"),
+            #[allow(deprecated)]
+            Token::LeftBrace => write!(f, "{{"),
+            #[allow(deprecated)]
+            Token::RightBrace => write!(f, "}}"),
+            Token::LeftRef => write!(f, "left"),
+            Token::RightRef => write!(f, "right"),
+        }
+    }
+}
+
+/// Writes an element of a collection, passing the alternate flag along.
+fn write_nested(f: &mut fmt::Formatter, token: &Token) -> fmt::Result {
+    if f.alternate() {
+        write!(f, "{:#}", token)
+    } else {
+        write!(f, "{}", token)
+    }
+}
+
+/// Map keys that are names are written bare, as in `{a: 1}`.
+fn write_key(f: &mut fmt::Formatter, key: &Token) -> fmt::Result {
+    match key {
+        Token::String(s)
+            if s.starts_with(|ch: char| ch.is_alphabetic() || ch == '_')
+                && s.chars().all(|ch| ch.is_alphanumeric() || ch == '_')
+                && !matches!(s.as_str(), "left" | "right" | "this") =>
+        {
+            write!(f, "{}", s)
+        }
+        key => write!(f, "{}", key),
+    }
+}
+
+/// Formats a number in positional notation; `BigDecimal`'s own `Display`
+/// switches to exponents the tokenizer can't read.
+fn plain_number(num: &BigDecimal) -> String {
+    let (digits, scale) = num.as_bigint_and_exponent();
+    let sign = if digits.sign() == Sign::Minus { "-" } else { "" };
+    let digits = digits.magnitude().to_string();
+    if scale <= 0 {
+        let zeros = if digits == "0" { 0 } else { (-scale) as usize };
+        return format!("{}{}{}", sign, digits, "0".repeat(zeros));
+    }
+    let scale = scale as usize;
+    let padding = (scale + 1).saturating_sub(digits.len());
+    let padded = format!("{}{}", "0".repeat(padding), digits);
+    let (int_part, frac_part) = padded.split_at(padded.len() - scale);
+    format!("{}{}.{}", sign, int_part, frac_part)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OperatorType {
     Prefix,
     Infix,
@@ -38,117 +184,501 @@ pub enum Assoc {
     Right,
 }
 
+pub(crate) type OperatorFn = fn(&mut Vec<Token>, &mut Context) -> Result<(), Error>;
+
+/// What runs an operator: a built-in function or a closure registered with
+/// [`Machine::register_operator`].
+type Native = Rc<dyn Fn(&mut Vec<Token>, &mut Context) -> Result<(), Error>>;
+
 #[derive(Debug, Clone)]
-pub struct Operator {
+pub(crate) struct Operator {
     pub symbol: String,
     pub precedence: usize,
     pub assoc: Assoc,
     pub op_type: OperatorType,
-    pub func: fn(&mut Vec<Token>, &mut HashMap<String, Token>),
+    pub func: OperatorFn,
+}
+
+/// A step of a run: the token executed and the stack, as it is after the
+/// step for a tracer and before it for a debugger, with how many brace
+/// operator calls were running.
+pub struct Step<'a> {
+    pub token: &'a Token,
+    pub stack: &'a [Token],
+    pub depth: usize,
+    context: &'a Context,
+}
+
+impl Step<'_> {
+    /// The variables `depth` calls deep: the globals at 0, then for each
+    /// running call what it captured, its operands and what it bound.
+    pub fn bindings(&self, depth: usize) -> Option<&HashMap<String, Token>> {
+        self.context.frames.get(depth)
+    }
+
+    /// The name of the operator running `depth` calls deep, from 1.
+    pub fn operator(&self, depth: usize) -> Option<&str> {
+        let call = depth.checked_sub(1).and_then(|i| self.context.calls.get(i));
+        call.map(|(symbol, _)| symbol.as_str())
+    }
+
+    /// Where the body of the operator running `depth` calls deep is written
+    /// in the source that defined it.
+    pub fn definition(&self, depth: usize) -> Option<Span> {
+        let body = self.context.bodies.get(self.operator(depth)?)?;
+        Some(body.span)
+    }
+}
+
+/// Called after every step of a run, see [`Machine::set_tracer`].
+pub type Tracer = Rc<dyn Fn(&Step)>;
+
+/// Called before every step of a run, see [`Machine::set_debugger`]. An
+/// error stops the run.
+pub type StepHook = Rc<dyn Fn(&Step) -> Result<(), Error>>;
+
+/// Where the operators that print write to, see [`Machine::set_output`].
+pub type Output = Rc<RefCell<dyn Write>>;
+
+/// The compiled body of a brace operator, the names it may capture and
+/// where it is written.
+#[derive(Debug, Clone)]
+struct Body {
+    tokens: Vec<Token>,
+    names: Vec<String>,
+    span: Span,
 }
 
+/// A brace operator being run and the variables it captured.
+type Call = (String, Rc<[(String, Token)]>);
+
+/// What operators see while a program runs: the variables, the operator
+/// table and the compiled bodies of brace operators.
+pub(crate) struct Context {
+    /// Variables by scope: the globals, then a frame per operator call.
+    frames: Vec<HashMap<String, Token>>,
+    prefix: HashMap<String, Native>,
+    infix: HashMap<String, Native>,
+    postfix: HashMap<String, Native>,
+    bodies: HashMap<String, Body>,
+    /// The brace operators being run, innermost last, for `this`.
+    calls: Vec<Call>,
+    budget: Budget,
+    fs: FsPolicy,
+    tracer: Option<Tracer>,
+    debugger: Option<StepHook>,
+    output: Output,
+    /// The tests `name test {...}` defined, by name.
+    tests: Vec<(String, Token)>,
+}
+
+impl Context {
+    /// Looks a name up in the innermost frame, then in the globals. A call
+    /// sees what its operator captured, never its caller's variables.
+    pub fn get(&self, name: &str) -> Option<&Token> {
+        let local = self.frames.last().and_then(|frame| frame.get(name));
+        local.or_else(|| self.frames.first().and_then(|globals| globals.get(name)))
+    }
+
+    /// Binds a name in the innermost frame.
+    pub fn insert(&mut self, name: String, value: Token) -> Option<Token> {
+        self.frames.last_mut().and_then(|frame| frame.insert(name, value))
+    }
+
+    fn table(&mut self, op_type: &OperatorType) -> &mut HashMap<String, Native> {
+        match op_type {
+            OperatorType::Prefix => &mut self.prefix,
+            OperatorType::Infix => &mut self.infix,
+            OperatorType::Postfix => &mut self.postfix,
+        }
+    }
+}
+
+/// Reads and runs expressions. Operators defined by an expression stay
+/// defined for the ones run after it; variables bound with `:` don't, only
+/// those given to [`Machine::define`].
 pub struct Machine {
     operators: Vec<Operator>,
+    variables: HashMap<String, Token>,
+    bodies: HashMap<String, Body>,
+    /// Operators registered from Rust, by symbol and fixity.
+    natives: Vec<(String, OperatorType, Native)>,
+    limits: Limits,
+    fs: FsPolicy,
+    tracer: Option<Tracer>,
+    debugger: Option<StepHook>,
+    output: Output,
+    /// What `tokenize` has read and where, while `lexemes` asks for it.
+    lexed: Option<Vec<(Span, Class)>>,
+    /// The tests defined so far, by name, each a brace to run.
+    tests: Vec<(String, Token)>,
+    /// Groups open around the brace body `tokenize` is reading.
+    nesting: usize,
+    /// The symbols of the operators spelled without letters.
+    symbols: Symbols,
+}
+
+impl Default for Machine {
+    fn default() -> Machine {
+        Machine::new()
+    }
 }
 
 impl Machine {
     pub fn new() -> Machine {
-        Machine { operators: get_standard_operators().clone() }
-    }
-
-    fn add_operator(&mut self, op_str: String) -> Token {
-        // TODO this is capturing any leftxxx or rightxxx variable
-        let mut op_type = OperatorType::Postfix;
-        let mut op_func: fn(&mut Vec<Token>, &mut HashMap<String, Token>) = |stack, context| op_posfix_op(stack, context);
-        if op_str.contains("left") && op_str.contains("right") {
-            op_type = OperatorType::Infix;
-            op_func = |stack, context| op_infix_op(stack, context);
-        } else if op_str.contains(" right ") {
-            op_type = OperatorType::Prefix;
-            op_func = |stack, context| op_prefix_op(stack, context);
+        let operators = get_standard_operators();
+        let mut symbols = Symbols::default();
+        for operator in &operators {
+            symbols.insert(&operator.symbol);
+        }
+        Machine {
+            operators,
+            variables: HashMap::new(),
+            bodies: HashMap::new(),
+            natives: Vec::new(),
+            limits: Limits::default(),
+            fs: FsPolicy::default(),
+            tracer: None,
+            debugger: None,
+            output: Rc::new(RefCell::new(io::stdout())),
+            lexed: None,
+            tests: Vec::new(),
+            nesting: 0,
+            symbols,
+        }
+    }
+
+    /// Binds `name` to `value` for every expression this machine runs.
+    ///
+    /// ```
+    /// let mut machine = handrit::Machine::new();
+    /// machine.define("name", handrit::Token::String("hati".to_string()));
+    /// assert_eq!(machine.run("upper name").unwrap().to_string(), "\"HATI\"");
+    /// ```
+    pub fn define(&mut self, name: &str, value: Token) {
+        self.variables.insert(name.to_string(), value);
+    }
+
+    /// Bounds every expression this machine runs from now on. Going over a
+    /// limit fails the run with a limit error; the machine stays usable.
+    ///
+    /// ```
+    /// use handrit::{ErrorKind, Limits, Machine};
+    ///
+    /// let mut machine = Machine::new();
+    /// machine.set_limits(Limits { digits: Some(1000), ..Limits::default() });
+    /// assert_eq!(machine.run("2 ** 99999999").unwrap_err().kind, ErrorKind::Limit);
+    /// assert_eq!(machine.run("2 ** 10").unwrap().to_string(), "1024");
+    /// ```
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Sets which files `@` may read and `>>` may write. Every file is
+    /// allowed unless a policy says otherwise.
+    ///
+    /// ```
+    /// use handrit::{ErrorKind, FsPolicy, Machine};
+    ///
+    /// let mut machine = Machine::new();
+    /// machine.set_fs_policy(FsPolicy::Deny);
+    /// assert_eq!(machine.run("@ \"/etc/passwd\"").unwrap_err().kind, ErrorKind::Permission);
+    /// ```
+    pub fn set_fs_policy(&mut self, policy: FsPolicy) {
+        self.fs = policy;
+    }
+
+    /// Calls `tracer` after every step of the programs this machine runs, or
+    /// stops tracing with `None`.
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// let mut machine = handrit::Machine::new();
+    /// let steps = Rc::new(RefCell::new(Vec::new()));
+    /// let log = steps.clone();
+    /// machine.set_tracer(Some(Rc::new(move |step: &handrit::Step| {
+    ///     log.borrow_mut().push(format!("{} | {}", step.token, step.stack.len()));
+    /// })));
+    /// machine.run("1 + 2").unwrap();
+    /// assert_eq!(*steps.borrow(), ["1 | 1", "2 | 2", "+ | 1"]);
+    /// ```
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Calls `debugger` before every step of the programs this machine runs,
+    /// or stops with `None`. It may look around, wait for its user and stop
+    /// the run by returning an error; [`crate::Debugger`] does all that.
+    pub fn set_debugger(&mut self, debugger: Option<StepHook>) {
+        self.debugger = debugger;
+    }
+
+    /// Sends what `;`, `$`, `~` and `?` print to `output` instead of
+    /// standard output.
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// let mut machine = handrit::Machine::new();
+    /// let printed = Rc::new(RefCell::new(Vec::new()));
+    /// machine.set_output(printed.clone());
+    /// machine.run("1 ; 2").unwrap();
+    /// assert_eq!(*printed.borrow(), b";\n");
+    /// ```
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    /// Registers an operator implemented in Rust. It is used like a built-in:
+    /// `op_type` says which operands it takes, `left` and `right` for infix
+    /// ones, and `func` gets their values in that order. A symbol is one word
+    /// or a run of punctuation; registering it again with the same fixity
+    /// replaces the operator.
+    ///
+    /// ```
+    /// use handrit::{Assoc, Machine, OperatorType, Token};
+    ///
+    /// let mut machine = Machine::new();
+    /// let greeting = "hello".to_string();
+    /// machine.register_operator("greet", OperatorType::Prefix, 14, Assoc::Left, move |operands| {
+    ///     Ok(Token::String(format!("{} {}", greeting, operands[0])))
+    /// });
+    /// assert_eq!(machine.run("greet 3").unwrap().to_string(), "\"hello 3\"");
+    /// ```
+    pub fn register_operator(
+        &mut self,
+        symbol: &str,
+        op_type: OperatorType,
+        precedence: usize,
+        assoc: Assoc,
+        func: impl Fn(&[Token]) -> Result<Token, Error> + 'static,
+    ) {
+        let fixity = op_type.clone();
+        let native: Native = Rc::new(move |stack, context| {
+            let operands = match fixity {
+                OperatorType::Infix => {
+                    let (left, right) = pop_operands(stack, context)?;
+                    vec![left, right]
+                }
+                _ => vec![pop_value(stack, context)?],
+            };
+            stack.push(func(&operands)?);
+            Ok(())
+        });
+        self.operators.retain(|op| !(op.symbol == symbol && op.op_type == op_type));
+        self.natives
+            .retain(|(other, other_type, _)| !(other == symbol && *other_type == op_type));
+        self.operators.push(Operator {
+            symbol: symbol.to_string(),
+            precedence,
+            assoc,
+            op_type: op_type.clone(),
+            func: op_native,
+        });
+        self.natives.push((symbol.to_string(), op_type, native));
+        self.symbols.insert(symbol);
+    }
+
+    /// Registers an operator on numbers, see [`Machine::register_operator`].
+    /// Other operands are a type error.
+    ///
+    /// ```
+    /// use handrit::{BigDecimal, Machine, OperatorType};
+    ///
+    /// let mut machine = Machine::new();
+    /// machine.register_number("avg", OperatorType::Infix, 12, |operands| {
+    ///     Ok((&operands[0] + &operands[1]) / BigDecimal::from(2))
+    /// });
+    /// assert_eq!(machine.run("1 avg 2").unwrap().to_string(), "1.5");
+    /// ```
+    pub fn register_number(
+        &mut self,
+        symbol: &str,
+        op_type: OperatorType,
+        precedence: usize,
+        func: impl Fn(&[BigDecimal]) -> Result<BigDecimal, Error> + 'static,
+    ) {
+        let name = symbol.to_string();
+        self.register_operator(symbol, op_type, precedence, Assoc::Left, move |operands| {
+            let numbers = operands
+                .iter()
+                .map(|operand| match operand {
+                    Token::Number(num) => Ok(num.clone()),
+                    other => Err(Error::type_error(format!(
+                        "cannot apply {} to {}",
+                        name,
+                        type_name(other)
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            func(&numbers).map(Token::Number)
+        });
+    }
+
+    /// Registers an operator on strings, see [`Machine::register_operator`].
+    /// Other operands are a type error.
+    ///
+    /// ```
+    /// use handrit::{Machine, OperatorType};
+    ///
+    /// let mut machine = Machine::new();
+    /// machine.register_string("shout", OperatorType::Postfix, 14, |operands| {
+    ///     Ok(format!("{}!", operands[0].to_uppercase()))
+    /// });
+    /// assert_eq!(machine.run("\"hi\" shout").unwrap().to_string(), "\"HI!\"");
+    /// ```
+    pub fn register_string(
+        &mut self,
+        symbol: &str,
+        op_type: OperatorType,
+        precedence: usize,
+        func: impl Fn(&[&str]) -> Result<String, Error> + 'static,
+    ) {
+        let name = symbol.to_string();
+        self.register_operator(symbol, op_type, precedence, Assoc::Left, move |operands| {
+            let strings = operands
+                .iter()
+                .map(|operand| match operand {
+                    Token::String(s) => Ok(s.as_str()),
+                    other => Err(Error::type_error(format!(
+                        "cannot apply {} to {}",
+                        name,
+                        type_name(other)
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            func(&strings).map(Token::String)
+        });
+    }
+
+    /// Registers the operator a brace defines and compiles its body. Using
+    /// `left` and `right` makes it infix, `left` alone postfix and anything
+    /// else prefix; operators nested in it don't count. `offset` is where
+    /// the body starts in the source.
+    fn add_operator(&mut self, body: String, offset: usize) -> Result<String, Error> {
+        let symbol = format!("{{{}}}", body);
+        if self.bodies.contains_key(&symbol) {
+            if self.lexed.is_some() {
+                // Read again, for `lexemes` to see this copy of the body.
+                self.tokenize(&body, offset)?;
+            }
+            return Ok(symbol);
+        }
+        let idents = body_identifiers(&body, false);
+        let uses = |keyword: &str| idents.iter().any(|ident| ident == keyword);
+        let op_type = match (uses("left"), uses("right")) {
+            (true, true) => OperatorType::Infix,
+            (true, false) => OperatorType::Postfix,
+            _ => OperatorType::Prefix,
         };
-        let op_symbol = format!("{{{}}}", op_str.clone());
+        let tokens = self.tokenize(&body, offset)?;
+        let mut names = body_identifiers(&body, true);
+        names.sort();
+        names.dedup();
+        names.retain(|name| name != "this");
+        let compiled = Body {
+            tokens: self.shunting_yard(tokens),
+            names,
+            span: Span::new(offset, offset + body.len()),
+        };
+        self.bodies.insert(symbol.clone(), compiled);
+        self.operators.push(Operator {
+            symbol: symbol.clone(),
+            precedence: 20,
+            assoc: Assoc::Left,
+            op_type,
+            func: op_body,
+        });
+        Ok(symbol)
+    }
 
+    /// Makes `name` an operator running the body of `symbol`, for `name: {...}`.
+    fn add_alias(&mut self, name: &str, symbol: &str) {
+        let Some(operator) = get_operator(symbol, &self.operators) else {
+            return;
+        };
+        let op_type = operator.op_type.clone();
+        self.operators.retain(|op| op.symbol != name);
+        self.natives.retain(|(symbol, _, _)| symbol != name);
         self.operators.push(Operator {
-            symbol: op_symbol.clone(),
+            symbol: name.to_string(),
             precedence: 20,
             assoc: Assoc::Left,
-            op_type: op_type.clone(),
-            func: op_func,
+            op_type,
+            func: op_body,
         });
-        Token::Operator(op_symbol, op_type)
+        if let Some(body) = self.bodies.get(symbol).cloned() {
+            self.bodies.insert(name.to_string(), body);
+        }
     }
 
-    fn tokenize(&mut self, expression: &str) -> Vec<Token> {
+    /// Splits an expression into tokens. `offset` is where the expression
+    /// starts in the source, so spans point into the whole source.
+    fn tokenize(&mut self, expression: &str, offset: usize) -> Result<Vec<Token>, Error> {
         //        tokenize(expression, &self.operators)
         let mut tokens = Vec::new();
-        let mut chars = expression.chars().peekable();
+        let mut chars = expression.char_indices().peekable();
         //        let mut func_operator = Vec::new();
+        let mut groups = Vec::new();
 
-        while let Some(&ch) = chars.peek() {
+        while let Some(&(pos, ch)) = chars.peek() {
+            let read = tokens.len();
             match ch {
-                '0'..='9' | '.' => {
-                    let mut num_str = String::new();
-                    while let Some(&digit) = chars.peek() {
-                        if digit.is_numeric() || digit == '.' {
-                            num_str.push(digit);
-                            chars.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    tokens.push(Token::Number(BigDecimal::from_str(&num_str).unwrap()));
+                '0'..='9' => tokens.push(lex_number(&mut chars)),
+                '.' if !follows_operand(&tokens) && starts_fraction(&chars) => {
+                    tokens.push(lex_number(&mut chars))
                 }
                 '"' => {
                     chars.next(); // skip the opening quote
-                    let mut str_val = String::new();
-                    while let Some(&ch) = chars.peek() {
-                        if ch == '"' {
-                            chars.next(); // skip the closing quote
-                            break;
-                        }
-                        str_val.push(ch);
-                        chars.next();
-                    }
-                    tokens.push(Token::String(str_val));
+                    let s = lex_string(&mut chars, pos).map_err(|e| e.shifted(offset))?;
+                    tokens.push(Token::String(s));
+                }
+                '\'' => {
+                    chars.next(); // skip the opening quote
+                    let s = lex_char(&mut chars, pos).map_err(|e| e.shifted(offset))?;
+                    tokens.push(Token::String(s));
                 }
                 '(' => {
                     tokens.push(Token::LeftParen);
+                    groups.push(ch);
+                    self.check_nesting(groups.len(), offset + pos)?;
                     chars.next();
                 }
                 ')' => {
                     tokens.push(Token::RightParen);
+                    groups.pop();
                     chars.next();
                 }
                 '[' => {
                     tokens.push(Token::LeftBracket);
+                    groups.push(ch);
+                    self.check_nesting(groups.len(), offset + pos)?;
                     chars.next();
                 }
                 ']' => {
                     tokens.push(Token::RightBracket);
+                    groups.pop();
                     chars.next();
                 }
                 '{' => {
                     chars.next(); // skip the opening brace
-                    let mut func_str = String::new();
-                    while let Some(&ch) = chars.peek() {
-                        if ch == '}' {
-                            chars.next(); // skip the closing brace
-                            break;
-                        }
-                        func_str.push(ch);
-                        chars.next();
-                    }
-                    let op = &self.add_operator(func_str);
-                    tokens.push(op.clone());
+                    let body = lex_brace_body(&mut chars, pos).map_err(|e| e.shifted(offset))?;
+                    let open = groups.len() + 1;
+                    self.check_nesting(open, offset + pos)?;
+                    self.nesting += open;
+                    let read = self.brace_tokens(body, &tokens, offset + pos + 1);
+                    self.nesting -= open;
+                    tokens.extend(read?);
                 }
                 '}' => {
-                    tokens.push(Token::RightBrace);
-                    chars.next();
+                    return Err(Error::lex("unmatched }", Span::new(offset + pos, offset + pos + 1)))
                 }
                 'a'..='z' | 'A'..='Z' | '_' => {
                     let mut ident = String::new();
-                    while let Some(&ch) = chars.peek() {
+                    while let Some(&(_, ch)) = chars.peek() {
                         if ch.is_alphanumeric() || ch == '_' {
                             ident.push(ch);
                             chars.next();
@@ -156,52 +686,197 @@ impl Machine {
                             break;
                         }
                     }
-                    if ident == "left" {
+                    let span = Span::new(offset + pos, offset + pos + ident.len());
+                    if ident == "r" && starts_raw_string(&chars) {
+                        let s = lex_raw_string(&mut chars, pos).map_err(|e| e.shifted(offset))?;
+                        tokens.push(Token::String(s));
+                    } else if ident == "true" || ident == "false" {
+                        tokens.push(bool_token(ident == "true"));
+                    } else if ident == "left" {
                         tokens.push(Token::LeftRef);
                     } else if ident == "right" {
                         tokens.push(Token::RightRef);
+                    } else if self.bodies.contains_key(&ident) && starts_binding(&chars) {
+                        // `name: {...}` again redefines the operator
+                        tokens.push(Token::Variable(ident, span));
+                    } else if let Some(operator) = self.find_operator(&ident, &tokens) {
+                        tokens.push(Token::Operator(ident, operator.op_type.clone()));
                     } else {
-                        tokens.push(Token::Variable(ident));
+                        tokens.push(Token::Variable(ident, span));
                     }
                 }
+                _ if ch.is_whitespace() => {
+                    chars.next();
+                }
                 _ => {
-                    let mut op = String::new();
-                    while let Some(&next_ch) = chars.peek() {
-                        if next_ch.is_alphanumeric()
-                            || next_ch == ' '
-                            || next_ch == '('
-                            || next_ch == ')'
-                            || next_ch == '['
-                            || next_ch == ']'
-                            || next_ch == '{'
-                            || next_ch == '}'
-                    {
-                        break;
+                    // The longest operator symbol written here: `1+-1` is
+                    // `1 + -1`, whatever the spacing.
+                    let Some(len) = self.symbols.longest(&expression[pos..]) else {
+                        let span = Span::new(offset + pos, offset + pos + ch.len_utf8());
+                        return Err(Error::lex(format!("unknown operator {}", ch), span));
+                    };
+                    let op = expression[pos..pos + len].to_string();
+                    while chars.next_if(|&(next, _)| next < pos + len).is_some() {}
+                    if get_prefix_operator(&op, &self.operators).is_none() {
+                        self.name_as_operand(&mut tokens, &expression[..pos], offset);
                     }
-                        op.push(next_ch);
-                        chars.next();
-                    }
-                    match tokens.last() {
-                        Some(Token::Operator(_, _)) | Some(Token::LeftParen) | None => {
-                            if let Some(operator) = get_prefix_operator(&op, &self.operators) {
-                                tokens.push(Token::Operator(op, operator.op_type.clone()));
-                            } else {
-                                chars.next(); // skip any unrecognized character
-                            }
-                        }
+                    // In brackets, `[1 -2]` holds two elements: an operator with
+                    // space before it and none after is a prefix one.
+                    let sign = groups.last() == Some(&'[')
+                        && expression[..pos].ends_with(char::is_whitespace)
+                        && chars.peek().is_some_and(|&(_, next)| !next.is_whitespace());
+                    let operator = match get_prefix_operator(&op, &self.operators) {
+                        Some(operator) if sign => Some(operator),
+                        _ => self.find_operator(&op, &tokens),
+                    };
+                    let Some(operator) = operator else {
+                        let fixity = if follows_operand(&tokens) { "infix" } else { "prefix" };
+                        let span = Span::new(offset + pos, offset + pos + len);
+                        return Err(Error::lex(format!("unknown {} operator {}", fixity, op), span));
+                    };
+                    tokens.push(Token::Operator(op, operator.op_type.clone()));
+                }
+            }
+            // A brace's pieces are recorded as its body is read.
+            if ch != '{' {
+                let end = chars.peek().map_or(expression.len(), |&(end, _)| end);
+                self.record(&tokens[read..], Span::new(offset + pos, offset + end));
+            }
+        }
+        Ok(tokens)
+    }
 
-                        _ => {
-                            if let Some(operator) = get_infix_operator(&op, &self.operators) {
-                                tokens.push(Token::Operator(op, operator.op_type.clone()));
-                            } else {
-                                chars.next(); // skip any unrecognized character
-                            }
-                        }
+    /// Before an operator that can't be prefix, makes a name bound to a
+    /// brace, read as a prefix operator, the value it is bound to again:
+    /// `f . 5` applies the brace `f` names. `before` is the expression up to
+    /// the operator.
+    fn name_as_operand(&self, tokens: &mut [Token], before: &str, offset: usize) {
+        if let Some(last) = tokens.last_mut() {
+            if let Token::Operator(name, OperatorType::Prefix) = last {
+                if self.bodies.contains_key(name.as_str()) {
+                    let end = offset + before.trim_end().len();
+                    let span = Span::new(end - name.len(), end);
+                    *last = Token::Variable(std::mem::take(name), span);
+                }
+            }
+        }
+    }
+
+    /// Fails when `open` more groups, the last at `pos`, would nest deeper
+    /// than [`MAX_NESTING`].
+    fn check_nesting(&self, open: usize, pos: usize) -> Result<(), Error> {
+        if self.nesting + open > MAX_NESTING {
+            return Err(too_deep(pos));
+        }
+        Ok(())
+    }
+
+    /// The tokens of a brace whose body starts at `offset`: a list literal
+    /// for a map literal, otherwise the operator the body defines, named
+    /// after a binding in `tokens` before it.
+    fn brace_tokens(
+        &mut self,
+        body: String,
+        tokens: &[Token],
+        offset: usize,
+    ) -> Result<Vec<Token>, Error> {
+        if is_map_literal(&body) {
+            return self.map_literal_tokens(&body, offset);
+        }
+        let symbol = self.add_operator(body, offset)?;
+        if let Some(name) = bound_name(tokens) {
+            self.add_alias(&name, &symbol);
+        }
+        Ok(vec![Token::Function(symbol, Rc::default())])
+    }
+
+    /// Notes what `tokens`, read from `span`, are for `lexemes`.
+    fn record(&mut self, tokens: &[Token], span: Span) {
+        if self.lexed.is_none() {
+            return;
+        }
+        let classes: Vec<(Span, Class)> = tokens.iter().filter_map(|token| {
+            let class = match token {
+                Token::Number(_) => Class::Number,
+                Token::String(_) => Class::String,
+                Token::Variable(_, _) => Class::Name,
+                Token::LeftRef | Token::RightRef => Class::Keyword,
+                Token::Operator(op, _) if op == "this" => Class::Keyword,
+                Token::Operator(op, _)
+                    if self.bodies.contains_key(op)
+                        || self.natives.iter().any(|(symbol, _, _)| symbol == op) =>
+                {
+                    Class::Operator
+                }
+                Token::Operator(_, _) => Class::Builtin,
+                _ => return None,
+            };
+            Some((span, class))
+        }).collect();
+        if let Some(lexed) = &mut self.lexed {
+            lexed.extend(classes);
+        }
+    }
+
+    /// Expands a `{key: value, ...}` literal into the tokens of a list literal
+    /// holding one entry per key. `offset` is where the body starts in the source.
+    fn map_literal_tokens(&mut self, body: &str, offset: usize) -> Result<Vec<Token>, Error> {
+        let mut tokens = vec![Token::LeftBracket];
+        for (entry_start, entry) in split_top_level(body, ',') {
+            if entry.trim().is_empty() {
+                continue;
+            }
+            let mut value_start = entry_start;
+            let mut value = entry;
+            if let Some((key_text, value_text)) = split_top_level(entry, ':')
+                .get(1)
+                .map(|&(colon_end, _)| (&entry[..colon_end - 1], &entry[colon_end..]))
+            {
+                if let Some(key) = parse_key(key_text) {
+                    let indent = key_text.len() - key_text.trim_start().len();
+                    let key_start = offset + entry_start + indent;
+                    let key_span = Span::new(key_start, key_start + key_text.trim().len());
+                    let colon = offset + entry_start + key_text.len();
+                    if let Some(lexed) = &mut self.lexed {
+                        let quoted = key_text.trim().starts_with('"');
+                        let class = match key {
+                            Token::String(_) if !quoted => Class::Name,
+                            Token::String(_) => Class::String,
+                            _ => Class::Number,
+                        };
+                        lexed.push((key_span, class));
+                        lexed.push((Span::new(colon, colon + 1), Class::Builtin));
                     }
+                    tokens.push(key);
+                    tokens.push(Token::Operator(":".to_string(), OperatorType::Infix));
+                    value_start += key_text.len() + 1;
+                    value = value_text;
                 }
             }
+            let value_tokens = self.tokenize(value, offset + value_start)?;
+            if value_tokens.is_empty() {
+                return Err(Error::lex(
+                    "missing value in map literal",
+                    Span::new(offset + entry_start, offset + entry_start + entry.len()),
+                ));
+            }
+            tokens.push(Token::LeftParen);
+            tokens.extend(value_tokens);
+            tokens.push(Token::RightParen);
+        }
+        tokens.push(Token::RightBracket);
+        Ok(tokens)
+    }
+
+    /// Finds the operator `symbol` names at this point of the token stream:
+    /// a prefix operator where an operand is expected, an infix one after an operand.
+    fn find_operator(&self, symbol: &str, tokens: &[Token]) -> Option<&Operator> {
+        if follows_operand(tokens) {
+            get_infix_operator(symbol, &self.operators)
+                .or_else(|| get_postfix_operator(symbol, &self.operators))
+        } else {
+            get_prefix_operator(symbol, &self.operators)
         }
-        tokens
     }
 
     fn shunting_yard(&self, tokens: Vec<Token>) -> Vec<Token> {
@@ -209,21 +884,51 @@ impl Machine {
         let mut output = Vec::new();
         let mut op_stack = Vec::new();
 
+        let mut prev_ends_operand = false;
+        let comma = Token::Operator(",".to_string(), OperatorType::Infix);
+
         for token in tokens {
+            // Inside brackets, elements are separated by whitespace only: an
+            // operand right after another one closes the previous element.
+            if prev_ends_operand && starts_operand(&token) && in_brackets(&op_stack) {
+                while let Some(top_op) = op_stack.last() {
+                    if *top_op == Token::LeftBracket {
+                        break;
+                    }
+                    emit(&mut output, op_stack.pop().unwrap());
+                }
+            }
+            prev_ends_operand = ends_operand(&token);
             match token.clone() {
                 Token::Number(_)
             | Token::String(_)
-            | Token::Variable(_)
+            | Token::Variable(_, _)
             | Token::List(_)
+            | Token::Entry(_, _)
+            | Token::Function(_, _)
+            | Token::Thunk(_)
             | Token::LeftRef
             | Token::RightRef => output.push(token),
+                #[allow(deprecated)]
+                Token::LeftBrace | Token::RightBrace => output.push(token),
                 Token::Operator(op, op_type) => match op_type {
                     OperatorType::Prefix => op_stack.push(token),
                     OperatorType::Postfix => {
                         output.push(token);
                     }
                     OperatorType::Infix => {
+                        // An entry with a literal key ends at a comma, so
+                        // `1: "a", 2: "b"` lists two entries.
+                        if op == "," && ends_entry(&op_stack, &output) {
+                            emit(&mut output, op_stack.pop().unwrap());
+                        }
                         while let Some(top_op) = op_stack.last() {
+                            if op == "," && *top_op == token {
+                                break; // the next element of a list under way
+                            }
+                            if op == ":" && top_op == &comma && has_literal_key(&output) {
+                                break; // an entry as the next element
+                            }
                             if let Token::Operator(top_op_str, _) = top_op {
                                 if let Some(top_operator) = get_operator(top_op_str, &self.operators) {
                                     if (top_operator.assoc == Assoc::Left
@@ -231,7 +936,7 @@ impl Machine {
                                         || (top_operator.assoc == Assoc::Right
                                             && top_operator.precedence > precedence(&op, &self.operators))
                                 {
-                                    output.push(op_stack.pop().unwrap());
+                                    emit(&mut output, op_stack.pop().unwrap());
                                 } else {
                                     break;
                                 }
@@ -240,7 +945,15 @@ impl Machine {
                                 break;
                             }
                         }
-                        op_stack.push(token);
+                        if op != "," {
+                            op_stack.push(token);
+                        } else if op_stack.last() != Some(&token) {
+                            // A new list starts at its first element, already
+                            // in the output; its `]` is emitted when the `,`
+                            // leaves the operator stack.
+                            output.insert(operand_start(&output), Token::LeftBracket);
+                            op_stack.push(token);
+                        }
                     }
                 },
                 Token::LeftParen => op_stack.push(token),
@@ -249,10 +962,9 @@ impl Machine {
                         if top_op == Token::LeftParen {
                             break;
                         }
-                        output.push(top_op);
+                        emit(&mut output, top_op);
                     }
                 }
-                /*
                 Token::LeftBracket => {
                     output.push(Token::LeftBracket);
                     op_stack.push(token);
@@ -262,276 +974,1931 @@ impl Machine {
                         if top_op == Token::LeftBracket {
                             break;
                         }
-                        output.push(top_op);
+                        emit(&mut output, top_op);
                     }
                     output.push(Token::RightBracket);
                 }
-                Token::LeftBrace => {
-                    output.push(Token::LeftBrace);
-                    op_stack.push(token);
-                }
-                Token::RightBrace => {
-                    while let Some(top_op) = op_stack.pop() {
-                        if top_op == Token::LeftBrace {
-                            break;
-                        }
-                        output.push(top_op);
-                    }
-                    output.push(Token::RightBrace);
-                }
-                 */
-                _ => todo!()
             }
         }
 
         while let Some(op) = op_stack.pop() {
-            output.push(op);
+            emit(&mut output, op);
         }
 
         output
     }
 
-    fn evaluate(&self, tokens: Vec<Token>) -> Result<Token, String> {
-        //        evaluate_rpn(tokens, &self.operators)
-        let mut stack = Vec::new();
-        let mut prefix_map: HashMap<String, fn(&mut Vec<Token>, &mut HashMap<String, Token>)> =
-            HashMap::new();
-        let mut infix_map: HashMap<String, fn(&mut Vec<Token>, &mut HashMap<String, Token>)> =
-            HashMap::new();
-        let mut postfix_map: HashMap<String, fn(&mut Vec<Token>, &mut HashMap<String, Token>)> =
-            HashMap::new();
-        let mut context: HashMap<String, Token> = HashMap::new();
+    fn evaluate(&mut self, tokens: Vec<Token>) -> Result<Token, Error> {
+        let mut context = self.context();
+        let result = execute(&tokens, &mut context);
+        for (name, test) in context.tests {
+            self.tests.retain(|(other, _)| *other != name);
+            self.tests.push((name, test));
+        }
+        result
+    }
 
+    /// A context to run code in, holding this machine's variables, operators
+    /// and settings.
+    fn context(&self) -> Context {
+        let mut context = Context {
+            frames: vec![self.variables.clone()],
+            prefix: HashMap::new(),
+            infix: HashMap::new(),
+            postfix: HashMap::new(),
+            bodies: self.bodies.clone(),
+            calls: Vec::new(),
+            budget: Budget::new(&self.limits),
+            fs: self.fs.clone(),
+            tracer: self.tracer.clone(),
+            debugger: self.debugger.clone(),
+            output: self.output.clone(),
+            tests: Vec::new(),
+        };
         for op in &self.operators {
-            match op.op_type {
-                OperatorType::Prefix => {
-                    prefix_map.insert(op.symbol.clone(), op.func);
-                }
-                OperatorType::Infix => {
-                    infix_map.insert(op.symbol.clone(), op.func);
-                }
-                OperatorType::Postfix => {
-                    postfix_map.insert(op.symbol.clone(), op.func);
-                }
-            }
+            let table = context.table(&op.op_type);
+            table.entry(op.symbol.clone()).or_insert_with(|| Rc::new(op.func));
         }
-        for token in tokens {
-            match token {
-                Token::Number(_) | Token::String(_) | Token::Variable(_) | Token::List(_) => {
-                    stack.push(token)
-                }
-                Token::Operator(op, op_type) => match op_type {
-                    OperatorType::Prefix => {
-                        if let Some(&func) = prefix_map.get(&op) {
-                            func(&mut stack, &mut context);
-                        } else {
-                            return Err(format!("Unknown prefix operator: {}", op));
-                        }
-                    }
-                    OperatorType::Infix => {
-                        if let Some(&func) = infix_map.get(&op) {
-                            func(&mut stack, &mut context);
-                        } else {
-                            return Err(format!("Unknown infix operator: {}", op));
-                        }
-                    }
-                    OperatorType::Postfix => {
-                        if let Some(&func) = postfix_map.get(&op) {
-                            func(&mut stack, &mut context);
-                        } else {
-                            return Err(format!("Unknown postfix operator: {}", op));
-                        }
-                    }
-                },
-                _ => return Err("Unexpected token".to_string()),
-            }
+        for (symbol, op_type, native) in &self.natives {
+            context.table(op_type).insert(symbol.clone(), native.clone());
         }
-
-        stack.pop().ok_or_else(|| "[]".to_string())
+        context
     }
 
-    pub fn to_rpn(&mut self, expression: &str) -> String {
-        let tokens = self.tokenize(expression);
-        let rpn_tokens = self.shunting_yard(tokens);
-
-        let mut result = String::new();
-        for token in rpn_tokens {
-            match token {
-                Token::Number(num) => result.push_str(&format!("{} ", num)),
-                Token::String(s) => result.push_str(&format!("\"{}\" ", s)),
-                Token::Variable(var) => result.push_str(&format!("{} ", var)),
-                Token::Operator(op, _) => result.push_str(&format!("{} ", op)),
-                Token::LeftParen => result.push_str(&format!("{} ", "(")),
-                Token::RightParen => result.push_str(&format!("{} ", ")")),
-                Token::LeftBracket => result.push_str(&format!("{} ", "[")),
-                Token::RightBracket => result.push_str(&format!("{} ", "]")),
-                Token::LeftBrace => result.push_str(&format!("{} ", "{")),
-                Token::RightBrace => result.push_str(&format!("{} ", "}")),
-                Token::LeftRef => result.push_str(&format!("{} ", "left")),
-                Token::RightRef => result.push_str(&format!("{} ", "right")),
-                _ => {}
-            }
-        }
-
-        result.trim().to_string()
+    /// The names of the tests defined by the expressions run so far, with
+    /// `name test {...}`, in the order they were defined.
+    pub fn tests(&self) -> Vec<&str> {
+        self.tests.iter().map(|(name, _)| name.as_str()).collect()
     }
-    pub fn run(&mut self, expression: &str) -> Result<Token, String> {
-        let tokens = self.tokenize(expression);
-        let rpn_tokens = self.shunting_yard(tokens);
-        let result = self.evaluate(rpn_tokens);
-        result
+
+    /// Runs the test named `name` and returns the value of its brace.
+    ///
+    /// ```
+    /// use handrit::{ErrorKind, Machine};
+    ///
+    /// let mut machine = Machine::new();
+    /// machine.run(r#"[("adds" test {assert (1 + 1 = 2)}) ("fails" test {"oops" assert 0})]"#)?;
+    /// assert_eq!(machine.tests(), ["adds", "fails"]);
+    /// assert_eq!(machine.run_test("adds")?.to_string(), "1");
+    /// let error = machine.run_test("fails").unwrap_err();
+    /// assert_eq!((error.kind, error.message.as_str()), (ErrorKind::Assertion, "oops"));
+    /// # Ok::<(), handrit::Error>(())
+    /// ```
+    pub fn run_test(&mut self, name: &str) -> Result<Token, Error> {
+        let test = self.tests.iter().find(|(other, _)| other == name);
+        let Some((_, Token::Function(symbol, captured))) = test.cloned() else {
+            return Err(Error::eval(format!("no test named {}", name)));
+        };
+        let mut context = self.context();
+        call_operator(symbol, &captured, None, None, &mut context)
     }
-}
 
-trait NumericValue {
-    fn n_value(self) -> BigDecimal;
-    fn b_value(self) -> bool;
-}
+    /// Compiles an expression to the postfix program it runs as, without
+    /// running it.
+    ///
+    /// ```
+    /// let mut machine = handrit::Machine::new();
+    /// assert_eq!(machine.to_rpn("1 - -2").unwrap(), "1 2 u- -");
+    /// ```
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_rpn(&mut self, expression: &str) -> Result<String, Error> {
+        let tokens = self.tokenize(&uncommented(expression), 0)?;
+        let rpn_tokens = self.shunting_yard(tokens);
 
-impl NumericValue for BigDecimal {
-    fn n_value(self) -> BigDecimal {
-        self
+        let words: Vec<String> = rpn_tokens.iter().map(|token| self.rpn_text(token)).collect();
+        Ok(words.join(" "))
     }
-    fn b_value(self) -> bool {
-        self != BigDecimal::zero()
-    }
-}
 
-impl NumericValue for String {
-    fn n_value(self) -> BigDecimal {
-        BigDecimal::from_usize(self.len()).expect("TODO!")
+    /// Reads `source` as `run` would, defining the operators it names,
+    /// without running it: lex errors and unbalanced groups are reported.
+    pub(crate) fn check(&mut self, source: &str) -> Result<(), Error> {
+        let source = uncommented(source);
+        check_groups(&source)?;
+        self.tokenize(&source, 0).map(|_| ())
     }
-    fn b_value(self) -> bool {
-        BigDecimal::from_usize(self.len()).expect("TODO!") != BigDecimal::zero()
-    }
-}
 
-impl NumericValue for Vec<Token> {
-    fn n_value(self) -> BigDecimal {
-        BigDecimal::from_usize(self.len()).expect("TODO!")
+    /// Reads `source` as `run` would, without running it, and tells what
+    /// each piece of it is, the insides of braces included, in the order
+    /// they are written. Whitespace, groups and braces are left out.
+    ///
+    /// ```
+    /// use handrit::{highlight::Class, Machine, Span};
+    ///
+    /// let lexemes = Machine::new().lexemes("x * 2 # twice").unwrap();
+    /// let classes: Vec<Class> = lexemes.iter().map(|&(_, class)| class).collect();
+    /// assert_eq!(classes, [Class::Name, Class::Builtin, Class::Number, Class::Comment]);
+    /// assert_eq!(lexemes[3].0, Span::new(6, 13));
+    /// ```
+    pub fn lexemes(&mut self, source: &str) -> Result<Vec<(Span, Class)>, Error> {
+        let comments = comments(source).into_iter().map(|span| (span, Class::Comment));
+        self.lexed = Some(comments.collect());
+        let result = self.tokenize(&uncommented(source), 0);
+        let mut lexed = self.lexed.take().unwrap_or_default();
+        result?;
+        lexed.sort_by_key(|&(span, _)| span.start);
+        Ok(lexed)
     }
-    fn b_value(self) -> bool {
-        BigDecimal::from_usize(self.len()).expect("TODO!") != BigDecimal::zero()
+
+    /// The operator table: the built-ins, those registered and those defined
+    /// by the expressions run so far.
+    pub(crate) fn operators(&self) -> &[Operator] {
+        &self.operators
     }
-}
 
-fn op_infix_numeric_value(stack: &mut Vec<Token>, context: &mut HashMap<String, Token>) {
-    let right = stack.pop().unwrap();
-    let left = stack.pop().unwrap();
-    match (left, right) {
-        (Token::Number(left_num), Token::Number(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::Number(left_num), Token::String(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::String(left_num), Token::Number(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::String(left_num), Token::String(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::List(left_num), Token::Number(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::List(left_num), Token::String(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::Number(left_num), Token::List(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::String(left_num), Token::List(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::List(left_num), Token::List(right_num)) => {
-            stack.push(Token::Number(BigDecimal::from(left_num.n_value())));
-            stack.push(Token::Number(BigDecimal::from(right_num.n_value())));
-        }
-        (Token::Variable(left_var), right) => {
-            let left_value = context.get(&left_var).clone();
-            // TODO maybe we can avoid undefined variable error here by pushing the variable again?
-            stack.push(left_value.expect("No lvalue").clone());
-            stack.push(right);
-            op_infix_numeric_value(stack, context);
-        }
-        (left, Token::Variable(right_var)) => {
-            let right_value = context.get(&right_var).clone();
-            // TODO maybe we can avoid undefined variable error here by pushing the variable again?
-            stack.push(left);
-            stack.push(right_value.expect("No rvalue").clone());
-            op_infix_numeric_value(stack, context);
-        }
-        _ => {
-            panic!("Add operation requires either numbers or strings");
+    fn rpn_text(&self, token: &Token) -> String {
+        match token {
+            // `-` and `+` are also infix: their prefix forms read `u-` and `u+`.
+            Token::Operator(op, OperatorType::Prefix)
+                if get_infix_operator(op, &self.operators).is_some() =>
+            {
+                format!("u{}", op)
+            }
+            Token::Thunk(body) => {
+                let words: Vec<String> = body.iter().map(|token| self.rpn_text(token)).collect();
+                format!("( {} )", words.join(" "))
+            }
+            _ => format!("{:#}", token),
         }
     }
-}
-fn op_infix_number<F>(stack: &mut Vec<Token>, context: &mut HashMap<String, Token>, op: F)
-where
-    F: Fn(BigDecimal, BigDecimal) -> BigDecimal,
-{
-    op_infix_numeric_value(stack, context);
-    let right = stack.pop().unwrap();
-    let left = stack.pop().unwrap();
-    match (left, right) {
-        (Token::Number(left_num), Token::Number(right_num)) => {
-            stack.push(Token::Number(op(left_num, right_num)));
-        }
-        _ => panic!("Add operation requires numbers"),
+
+    /// Runs an expression and returns its value.
+    ///
+    /// ```
+    /// let mut machine = handrit::Machine::new();
+    /// assert_eq!(machine.run("[10 20 30] . 1").unwrap().to_string(), "20");
+    /// ```
+    pub fn run(&mut self, expression: &str) -> Result<Token, Error> {
+        let tokens = self.tokenize(&uncommented(expression), 0)?;
+        let rpn_tokens = self.shunting_yard(tokens);
+        self.evaluate(rpn_tokens)
     }
-}
 
-fn op_infix_bool<F>(stack: &mut Vec<Token>, context: &mut HashMap<String, Token>, op: F)
-where
-    F: Fn(bool, bool) -> bool,
-{
-    op_infix_numeric_value(stack, context);
-    let right = stack.pop().unwrap();
-    let left = stack.pop().unwrap();
-    match (left, right) {
-        (Token::Number(left_num), Token::Number(right_num)) => {
-            stack.push(Token::Number(
-                if op(left_num.b_value(), right_num.b_value()) {
-                    BigDecimal::one()
-                } else {
-                    BigDecimal::zero()
-                },
-            ));
-        }
-        _ => panic!("Add operation requires booleans"),
+    /// Executes a postfix program in the notation `to_rpn` writes.
+    ///
+    /// ```
+    /// let mut machine = handrit::Machine::new();
+    /// assert_eq!(machine.run_rpn("1 2 u- -").unwrap().to_string(), "3");
+    /// ```
+    pub fn run_rpn(&mut self, program: &str) -> Result<Token, Error> {
+        let tokens = self.tokenize_rpn(program)?;
+        self.evaluate(tokens)
     }
-}
 
-fn op_infix_op(stack: &mut Vec<Token>, context: &mut HashMap<String, Token>) {
-    let right = stack.pop().unwrap();
-    let left = stack.pop().unwrap();
-    context.insert(String::from("right"), right);
-    context.insert(String::from("left"), left);
-    stack.push(Token::String(String::from("undefined")));
-    context.remove("right");
-    context.remove("left");
-}
-fn op_prefix_op(stack: &mut Vec<Token>, context: &mut HashMap<String, Token>) {
-    let right = stack.pop().unwrap();
-    context.insert(String::from("right"), right);
-    stack.push(Token::String(String::from("undefined")));
-    context.remove("right");
+    /// Splits a postfix program into tokens. Words are separated by whitespace
+    /// and stand for literals, operators or variables; a prefix operator that
+    /// shares its symbol with an infix one is written with a `u` before it.
+    fn tokenize_rpn(&mut self, program: &str) -> Result<Vec<Token>, Error> {
+        let mut tokens = Vec::new();
+        let mut chars = program.char_indices().peekable();
+        // Tokens outside the thunks being read, with where each thunk opened.
+        let mut enclosing: Vec<(usize, Vec<Token>)> = Vec::new();
+        let mut brackets = 0;
+
+        while let Some(&(pos, ch)) = chars.peek() {
+            match ch {
+                _ if ch.is_whitespace() => {
+                    chars.next();
+                }
+                '"' => {
+                    chars.next(); // skip the opening quote
+                    tokens.push(Token::String(lex_string(&mut chars, pos)?));
+                }
+                '\'' => {
+                    chars.next(); // skip the opening quote
+                    tokens.push(Token::String(lex_char(&mut chars, pos)?));
+                }
+                '[' => {
+                    tokens.push(Token::LeftBracket);
+                    brackets += 1;
+                    self.check_nesting(brackets + enclosing.len(), pos)?;
+                    chars.next();
+                }
+                ']' => {
+                    tokens.push(Token::RightBracket);
+                    brackets = brackets.saturating_sub(1);
+                    chars.next();
+                }
+                '(' => {
+                    enclosing.push((pos, std::mem::take(&mut tokens)));
+                    self.check_nesting(brackets + enclosing.len(), pos)?;
+                    chars.next();
+                }
+                ')' => {
+                    let (_, mut outer) = enclosing
+                        .pop()
+                        .ok_or_else(|| Error::lex("unmatched )", Span::new(pos, pos + 1)))?;
+                    outer.push(Token::Thunk(std::mem::take(&mut tokens)));
+                    tokens = outer;
+                    chars.next();
+                }
+                '{' => {
+                    chars.next(); // skip the opening brace
+                    let body = lex_brace_body(&mut chars, pos)?;
+                    let symbol = self.add_operator(body, pos + 1)?;
+                    tokens.push(Token::Function(symbol, Rc::default()));
+                }
+                _ => {
+                    let mut word = String::new();
+                    while let Some(&(_, ch)) = chars.peek() {
+                        if ch.is_whitespace() || matches!(ch, '"' | '[' | ']' | '{' | '(' | ')') {
+                            break;
+                        }
+                        word.push(ch);
+                        chars.next();
+                    }
+                    let span = Span::new(pos, pos + word.len());
+                    if word == "r" && starts_raw_string(&chars) {
+                        tokens.push(Token::String(lex_raw_string(&mut chars, pos)?));
+                    } else if word == ":" {
+                        self.bind_rpn_operator(&mut tokens, span);
+                        tokens.push(self.rpn_word(&word, span)?);
+                    } else {
+                        tokens.push(self.rpn_word(&word, span)?);
+                    }
+                }
+            }
+        }
+        if let Some((pos, _)) = enclosing.pop() {
+            return Err(Error::lex("unclosed (", Span::new(pos, pos + 1)));
+        }
+        Ok(tokens)
+    }
+
+    /// Before a `:`, turns `name {...}` into a binding: the name, maybe read
+    /// as an operator defined earlier, is a variable again and becomes an
+    /// operator running the brace, as `name: {...}` does in infix. A name
+    /// read as an operator takes the span of the `:`.
+    fn bind_rpn_operator(&mut self, tokens: &mut [Token], colon: Span) {
+        if let [.., name_token, Token::Function(symbol, _)] = tokens {
+            let (name, span) = match name_token {
+                Token::Variable(name, span) => (name.clone(), *span),
+                Token::Operator(name, _) if self.bodies.contains_key(name) => (name.clone(), colon),
+                _ => return,
+            };
+            self.add_alias(&name, symbol);
+            *name_token = Token::Variable(name, span);
+        }
+    }
+
+    fn rpn_word(&self, word: &str, span: Span) -> Result<Token, Error> {
+        let digits = word.strip_prefix('-').unwrap_or(word);
+        if digits.starts_with(|ch: char| ch.is_ascii_digit())
+            || (digits.starts_with('.') && digits[1..].starts_with(|ch: char| ch.is_ascii_digit()))
+        {
+            return BigDecimal::from_str(word)
+                .map(Token::Number)
+                .map_err(|_| Error::lex(format!("invalid number {}", word), span));
+        }
+        match word {
+            "true" | "false" => return Ok(bool_token(word == "true")),
+            "left" => return Ok(Token::LeftRef),
+            "right" => return Ok(Token::RightRef),
+            _ => {}
+        }
+        if let Some(op) = word.strip_prefix('u') {
+            if get_infix_operator(op, &self.operators).is_some() {
+                if let Some(operator) = get_prefix_operator(op, &self.operators) {
+                    return Ok(Token::Operator(op.to_string(), operator.op_type.clone()));
+                }
+            }
+        }
+        let operator = get_infix_operator(word, &self.operators)
+            .or_else(|| get_postfix_operator(word, &self.operators))
+            .or_else(|| get_prefix_operator(word, &self.operators));
+        match operator {
+            Some(operator) => Ok(Token::Operator(word.to_string(), operator.op_type.clone())),
+            None if word.starts_with(|ch: char| ch.is_alphabetic() || ch == '_')
+                && word.chars().all(|ch| ch.is_alphanumeric() || ch == '_') =>
+            {
+                Ok(Token::Variable(word.to_string(), span))
+            }
+            None => Err(Error::lex(format!("unknown word {}", word), span)),
+        }
+    }
+}
+
+/// Runs postfix code and returns the value it leaves on top of the stack.
+fn execute(tokens: &[Token], context: &mut Context) -> Result<Token, Error> {
+    let mut stack = Vec::new();
+    for token in tokens {
+        context.budget.step()?;
+        if let Some(debugger) = context.debugger.clone() {
+            let depth = context.calls.len();
+            debugger(&Step { token, stack: &stack, depth, context })?;
+        }
+        match token {
+            Token::Number(_)
+            | Token::String(_)
+            | Token::Variable(_, _)
+            | Token::List(_)
+            | Token::Entry(_, _)
+            | Token::Thunk(_)
+            | Token::LeftBracket => stack.push(token.clone()),
+            Token::Function(symbol, _) => stack.push(capture(symbol, context)),
+            Token::LeftRef => stack.push(operand_binding(context, "left")?),
+            Token::RightRef => stack.push(operand_binding(context, "right")?),
+            Token::RightBracket => {
+                let list = collect_list(&mut stack, context)?;
+                stack.push(list);
+            }
+            Token::Operator(op, op_type) => {
+                let (symbol, captured) = match op.as_str() {
+                    "this" => context
+                        .calls
+                        .last()
+                        .cloned()
+                        .ok_or_else(|| Error::eval("this outside an operator"))?,
+                    // A name bound by `name: {...}` runs with what the brace captured.
+                    _ => match context.get(op) {
+                        Some(Token::Function(_, captured)) => (op.clone(), captured.clone()),
+                        _ => (op.clone(), Rc::default()),
+                    },
+                };
+                if context.bodies.contains_key(&symbol) {
+                    let right = match op_type {
+                        OperatorType::Postfix => None,
+                        _ => Some(pop_value(&mut stack, context)?),
+                    };
+                    let left = match op_type {
+                        OperatorType::Prefix => None,
+                        _ => Some(pop_value(&mut stack, context)?),
+                    };
+                    let value = call_operator(symbol, &captured, left, right, context)?;
+                    stack.push(value);
+                } else {
+                    let (table, fixity) = match op_type {
+                        OperatorType::Prefix => (&context.prefix, "prefix"),
+                        OperatorType::Infix => (&context.infix, "infix"),
+                        OperatorType::Postfix => (&context.postfix, "postfix"),
+                    };
+                    match table.get(op).cloned() {
+                        Some(func) => func(&mut stack, context)?,
+                        None => {
+                            return Err(Error::eval(format!(
+                                "Unknown {} operator: {}",
+                                fixity, op
+                            )))
+                        }
+                    }
+                }
+                if let Some(value) = stack.last() {
+                    context.budget.check(value)?;
+                }
+            }
+            _ => return Err(Error::eval("Unexpected token")),
+        }
+        if let Some(tracer) = context.tracer.clone() {
+            let depth = context.calls.len();
+            tracer(&Step { token, stack: &stack, depth, context });
+        }
+    }
+
+    if stack.is_empty() {
+        return Err(Error::eval("[]"));
+    }
+    if stack.contains(&Token::LeftBracket) {
+        return Err(Error::eval("unclosed ["));
+    }
+    pop_value(&mut stack, context)
+}
+
+/// The value of `left` or `right` in the operator being run. Operands are
+/// read when met, so a nested call rebinding them can't change them.
+fn operand_binding(context: &Context, name: &str) -> Result<Token, Error> {
+    context
+        .get(name)
+        .cloned()
+        .ok_or_else(|| Error::eval(format!("{} outside an operator", name)))
+}
+
+/// A brace evaluated to a value, holding a snapshot of the variables its
+/// body uses as they are at this point.
+fn capture(symbol: &str, context: &Context) -> Token {
+    let captured = context
+        .bodies
+        .get(symbol)
+        .map(|body| {
+            body.names
+                .iter()
+                .filter_map(|name| Some((name.clone(), context.get(name)?.clone())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Token::Function(symbol.to_string(), captured)
+}
+
+/// Runs the body of a brace operator in a frame of its own, holding its
+/// captured variables and its operands bound to `left` and `right`. An
+/// operand it doesn't take keeps its captured value.
+fn call_operator(
+    symbol: String,
+    captured: &Rc<[(String, Token)]>,
+    left: Option<Token>,
+    right: Option<Token>,
+    context: &mut Context,
+) -> Result<Token, Error> {
+    let body = match context.bodies.get(&symbol) {
+        Some(body) => body.tokens.clone(),
+        None => return Err(Error::eval(format!("unknown operator: {}", symbol))),
+    };
+    let mut frame: HashMap<String, Token> = captured.iter().cloned().collect();
+    for (name, operand) in [("left", left), ("right", right)] {
+        if let Some(value) = operand {
+            frame.insert(name.to_string(), value);
+        }
+    }
+    context.budget.depth(context.calls.len() + 1)?;
+    context.frames.push(frame);
+    context.calls.push((symbol, captured.clone()));
+    let result = execute(&body, context);
+    context.calls.pop();
+    context.frames.pop();
+    result
+}
+
+/// `f . x` runs the brace operator `f` with `x` as its operand: the right
+/// one for a prefix operator, the left one otherwise. An infix operator
+/// takes its right operand from what it captured.
+fn apply_function(
+    symbol: String,
+    captured: &Rc<[(String, Token)]>,
+    operand: Token,
+    context: &mut Context,
+) -> Result<Token, Error> {
+    let (left, right) = if context.prefix.contains_key(&symbol) {
+        (None, Some(operand))
+    } else if context.postfix.contains_key(&symbol)
+        || captured.iter().any(|(name, _)| name == "right")
+    {
+        (Some(operand), None)
+    } else {
+        return Err(Error::eval(format!("{} needs a right operand", symbol)));
+    };
+    call_operator(symbol, captured, left, right, context)
+}
+
+type Chars<'a> = Peekable<CharIndices<'a>>;
+
+fn starts_operand(token: &Token) -> bool {
+    !matches!(
+        token,
+        Token::RightParen
+            | Token::RightBracket
+            | Token::Operator(_, OperatorType::Infix)
+            | Token::Operator(_, OperatorType::Postfix)
+    )
+}
+
+fn ends_operand(token: &Token) -> bool {
+    !matches!(
+        token,
+        Token::LeftParen
+            | Token::LeftBracket
+            | Token::Operator(_, OperatorType::Infix)
+            | Token::Operator(_, OperatorType::Prefix)
+    )
+}
+
+/// Moves an operator from the operator stack to the output. A `,` there
+/// stands for a list being built, which ends here. The right operand of
+/// `&&`, `||` and of an entry with a literal key is deferred into a thunk.
+fn emit(output: &mut Vec<Token>, op: Token) {
+    match op {
+        Token::Operator(symbol, OperatorType::Infix) if symbol == "," => {
+            output.push(Token::RightBracket)
+        }
+        Token::Operator(symbol, OperatorType::Infix) if defers_right(&symbol, output) => {
+            let right = output.split_off(operand_start(output));
+            output.push(Token::Thunk(right));
+            output.push(Token::Operator(symbol, OperatorType::Infix));
+        }
+        op => output.push(op),
+    }
+}
+
+/// Whether the last operand in `output`, the right one of `symbol`, should
+/// only run on demand. Literals are left alone, they cost nothing.
+fn defers_right(symbol: &str, output: &[Token]) -> bool {
+    let right_start = operand_start(output);
+    if matches!(&output[right_start..], [Token::Number(_) | Token::String(_)]) {
+        return false;
+    }
+    match symbol {
+        "&&" | "||" | "expect_error" => true,
+        ":" => has_literal_key(&output[..right_start]),
+        _ => false,
+    }
+}
+
+/// Whether the last operand in `output` is a number or string literal.
+fn has_literal_key(output: &[Token]) -> bool {
+    matches!(
+        &output[operand_start(output)..],
+        [Token::Number(_) | Token::String(_)]
+    )
+}
+
+/// Whether the operator on top of `op_stack` is a `:` building an entry with
+/// a literal key, which a following `,` closes.
+fn ends_entry(op_stack: &[Token], output: &[Token]) -> bool {
+    match op_stack.last() {
+        Some(Token::Operator(symbol, OperatorType::Infix)) if symbol == ":" => {
+            has_literal_key(&output[..operand_start(output)])
+        }
+        _ => false,
+    }
+}
+
+/// Index in `output` (postfix order) where its last complete operand starts.
+fn operand_start(output: &[Token]) -> usize {
+    let mut needed = 1;
+    let mut i = output.len();
+    while i > 0 {
+        i -= 1;
+        match &output[i] {
+            Token::Operator(_, OperatorType::Infix) => needed += 1,
+            Token::Operator(_, _) => {}
+            Token::RightBracket => {
+                let mut depth = 0;
+                while i > 0 {
+                    match output[i] {
+                        Token::RightBracket => depth += 1,
+                        Token::LeftBracket => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    i -= 1;
+                }
+                needed -= 1;
+            }
+            _ => needed -= 1,
+        }
+        if needed == 0 {
+            return i;
+        }
+    }
+    0
+}
+
+/// Whether the innermost group still open on the operator stack is a bracket.
+fn in_brackets(op_stack: &[Token]) -> bool {
+    op_stack
+        .iter()
+        .rev()
+        .find(|token| matches!(token, Token::LeftParen | Token::LeftBracket))
+        == Some(&Token::LeftBracket)
+}
+
+/// Whether the last token ends an operand, so that what comes next is an
+/// infix or postfix position rather than the start of a new operand.
+fn follows_operand(tokens: &[Token]) -> bool {
+    !matches!(
+        tokens.last(),
+        Some(Token::Operator(_, OperatorType::Prefix | OperatorType::Infix))
+            | Some(Token::LeftParen)
+            | Some(Token::LeftBracket)
+            | None
+    )
+}
+
+/// Whether a `.` is the start of a number like `.5` rather than the dot operator.
+fn starts_fraction(chars: &Chars) -> bool {
+    let mut look = chars.clone();
+    look.next();
+    matches!(look.next(), Some((_, '0'..='9')))
+}
+
+/// Reads a decimal number. A `.` is only part of the number when a digit follows it.
+fn lex_number(chars: &mut Chars) -> Token {
+    let mut num_str = String::new();
+    while let Some(&(_, digit)) = chars.peek() {
+        if digit.is_ascii_digit()
+            || (digit == '.' && !num_str.contains('.') && starts_fraction(chars))
+        {
+            num_str.push(digit);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    Token::Number(BigDecimal::from_str(&num_str).unwrap_or_default())
+}
+
+/// Reads the body of a `"` string literal, the opening quote already consumed.
+/// Literals may span several lines.
+fn lex_string(chars: &mut Chars, start: usize) -> Result<String, Error> {
+    let mut str_val = String::new();
+    while let Some((pos, ch)) = chars.next() {
+        match ch {
+            '"' => return Ok(str_val),
+            '\\' => str_val.push(lex_escape(chars, pos)?),
+            _ => str_val.push(ch),
+        }
+    }
+    Err(Error::lex(
+        "unterminated string literal",
+        Span::new(start, start + 1),
+    ))
+}
+
+/// Reads a `'c'` character literal, the opening quote already consumed.
+/// Characters are strings of length one.
+fn lex_char(chars: &mut Chars, start: usize) -> Result<String, Error> {
+    let ch = match chars.next() {
+        Some((pos, '\\')) => lex_escape(chars, pos)?,
+        Some((_, '\'')) => {
+            return Err(Error::lex(
+                "empty character literal",
+                Span::new(start, start + 2),
+            ))
+        }
+        Some((_, ch)) => ch,
+        None => {
+            return Err(Error::lex(
+                "unterminated character literal",
+                Span::new(start, start + 1),
+            ))
+        }
+    };
+    match chars.next() {
+        Some((_, '\'')) => Ok(ch.to_string()),
+        Some((pos, _)) => Err(Error::lex(
+            "character literal must hold exactly one character",
+            Span::new(start, pos),
+        )),
+        None => Err(Error::lex(
+            "unterminated character literal",
+            Span::new(start, start + 1),
+        )),
+    }
+}
+
+/// Reads the character following a backslash.
+fn lex_escape(chars: &mut Chars, start: usize) -> Result<char, Error> {
+    let escaped = match chars.next() {
+        Some((_, 'n')) => '\n',
+        Some((_, 't')) => '\t',
+        Some((_, 'r')) => '\r',
+        Some((_, '0')) => '\0',
+        Some((_, '\\')) => '\\',
+        Some((_, '"')) => '"',
+        Some((_, '\'')) => '\'',
+        Some((_, 'u')) => return lex_unicode_escape(chars, start),
+        Some((pos, ch)) => {
+            return Err(Error::lex(
+                format!("unknown escape sequence: \\{}", ch),
+                Span::new(start, pos + ch.len_utf8()),
+            ))
+        }
+        None => {
+            return Err(Error::lex(
+                "unterminated escape sequence",
+                Span::new(start, start + 1),
+            ))
+        }
+    };
+    Ok(escaped)
+}
+
+/// Reads the `{XXXX}` part of a `\u{XXXX}` escape.
+fn lex_unicode_escape(chars: &mut Chars, start: usize) -> Result<char, Error> {
+    if !matches!(chars.next(), Some((_, '{'))) {
+        return Err(Error::lex(
+            "expected '{' after \\u",
+            Span::new(start, start + 2),
+        ));
+    }
+    let mut hex = String::new();
+    for (pos, ch) in chars.by_ref() {
+        if ch == '}' {
+            return u32::from_str_radix(&hex, 16)
+                .ok()
+                .filter(|_| (1..=6).contains(&hex.len()))
+                .and_then(char::from_u32)
+                .ok_or_else(|| {
+                    Error::lex(
+                        format!("invalid unicode escape: \\u{{{}}}", hex),
+                        Span::new(start, pos + 1),
+                    )
+                });
+        }
+        hex.push(ch);
+    }
+    Err(Error::lex(
+        "unterminated unicode escape",
+        Span::new(start, start + 2),
+    ))
+}
+
+/// Whether an `r` identifier is the prefix of a raw string: `r"..."` or `r#"..."#`.
+fn starts_raw_string(chars: &Chars) -> bool {
+    chars
+        .clone()
+        .map(|(_, ch)| ch)
+        .find(|&ch| ch != '#')
+        == Some('"')
+}
+
+/// Reads a raw string after its `r`. Backslashes are kept verbatim and the
+/// literal ends at a quote followed by as many `#` as opened it.
+fn lex_raw_string(chars: &mut Chars, start: usize) -> Result<String, Error> {
+    let mut hashes = 0;
+    while let Some((_, '#')) = chars.peek() {
+        hashes += 1;
+        chars.next();
+    }
+    chars.next(); // skip the opening quote
+    let mut str_val = String::new();
+    while let Some((_, ch)) = chars.next() {
+        if ch == '"' {
+            let mut look = chars.clone();
+            if (0..hashes).all(|_| matches!(look.next(), Some((_, '#')))) {
+                for _ in 0..hashes {
+                    chars.next();
+                }
+                return Ok(str_val);
+            }
+        }
+        str_val.push(ch);
+    }
+    Err(Error::lex(
+        "unterminated raw string literal",
+        Span::new(start, start + 2 + hashes),
+    ))
+}
+
+/// Reads the text between a `{` and its matching `}`, the opening brace
+/// already consumed. Braces inside string literals do not count; braces
+/// nested more than [`MAX_NESTING`] deep, this one included, are an error.
+fn lex_brace_body(chars: &mut Chars, start: usize) -> Result<String, Error> {
+    let mut body = String::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (pos, ch) in chars.by_ref() {
+        if let Some(open) = quote {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == open {
+                quote = None;
+            }
+        } else {
+            match ch {
+                '"' | '\'' => quote = Some(ch),
+                '{' if depth + 1 == MAX_NESTING => return Err(too_deep(pos)),
+                '{' => depth += 1,
+                '}' if depth == 0 => return Ok(body),
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        body.push(ch);
+    }
+    Err(Error::lex("unclosed {", Span::new(start, start + 1)))
+}
+
+/// The symbols of operators spelled without letters or digits, such as `+`
+/// and `<=`, as a trie for the tokenizer to read the longest one written.
+#[derive(Debug, Default)]
+struct Symbols {
+    next: HashMap<char, Symbols>,
+    /// Whether a symbol ends here.
+    end: bool,
+}
+
+impl Symbols {
+    /// Adds `symbol`, unless a character of it can't be part of one.
+    fn insert(&mut self, symbol: &str) {
+        if symbol.is_empty() || !symbol.chars().all(is_symbol_char) {
+            return;
+        }
+        let mut node = self;
+        for ch in symbol.chars() {
+            node = node.next.entry(ch).or_default();
+        }
+        node.end = true;
+    }
+
+    /// The length in bytes of the longest symbol `text` starts with.
+    fn longest(&self, text: &str) -> Option<usize> {
+        let mut node = self;
+        let mut longest = None;
+        for (pos, ch) in text.char_indices() {
+            match node.next.get(&ch) {
+                Some(next) => node = next,
+                None => break,
+            }
+            if node.end {
+                longest = Some(pos + ch.len_utf8());
+            }
+        }
+        longest
+    }
+}
+
+/// Whether `ch` may be part of an operator symbol: it isn't a letter, a
+/// digit, whitespace, a quote or a bracket.
+fn is_symbol_char(ch: char) -> bool {
+    !(ch.is_alphanumeric()
+        || ch == '_'
+        || ch.is_whitespace()
+        || matches!(ch, '(' | ')' | '[' | ']' | '{' | '}' | '"' | '\''))
+}
+
+/// The error for a group opening at `pos` deeper than [`MAX_NESTING`].
+fn too_deep(pos: usize) -> Error {
+    let message = format!("groups nested more than {} deep", MAX_NESTING);
+    Error::lex(message, Span::new(pos, pos + 1))
+}
+
+/// The characters of `text` outside any string literal and any (), [] or {}
+/// group, with their byte offsets.
+fn scan_top_level(text: &str) -> Vec<(usize, char)> {
+    let mut top = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (pos, ch) in text.char_indices() {
+        if let Some(open) = quote {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == open {
+                quote = None;
+            }
+            continue;
+        }
+        match ch {
+            '"' | '\'' => quote = Some(ch),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ if depth == 0 => top.push((pos, ch)),
+            _ => {}
+        }
+    }
+    top
+}
+
+/// Where the comments of `source` are: a `#` starting a word, outside string
+/// literals, runs to the end of its line.
+fn comments(source: &str) -> Vec<Span> {
+    let mut found = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    let mut previous = None;
+    while let Some((pos, ch)) = chars.next() {
+        match ch {
+            '"' | '\'' => {
+                let mut escaped = false;
+                for (_, next) in chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                    } else if next == '\\' {
+                        escaped = true;
+                    } else if next == ch {
+                        break;
+                    }
+                }
+            }
+            '#' if previous.is_none_or(char::is_whitespace) => {
+                let end = source[pos..].find('\n').map_or(source.len(), |len| pos + len);
+                found.push(Span::new(pos, end));
+                while chars.next_if(|&(next, _)| next < end).is_some() {}
+            }
+            _ => {}
+        }
+        previous = Some(ch);
+    }
+    found
+}
+
+/// `source` with its comments blanked out byte for byte, so that spans
+/// still point into it.
+pub(crate) fn uncommented(source: &str) -> Cow<'_, str> {
+    let comments = comments(source);
+    if comments.is_empty() {
+        return Cow::Borrowed(source);
+    }
+    let mut blanked = String::with_capacity(source.len());
+    let mut last = 0;
+    for span in comments {
+        blanked.push_str(&source[last..span.start]);
+        blanked.push_str(&" ".repeat(span.end - span.start));
+        last = span.end;
+    }
+    blanked.push_str(&source[last..]);
+    Cow::Owned(blanked)
+}
+
+/// Checks that the (), [] and {} groups of `source` close in the order they
+/// open, string literals left out.
+fn check_groups(source: &str) -> Result<(), Error> {
+    match open_groups(source)?.pop() {
+        Some((pos, ch)) => Err(Error::lex(format!("unclosed {}", ch), Span::new(pos, pos + 1))),
+        None => Ok(()),
+    }
+}
+
+/// Whether `source` leaves a group open, so that the next line goes on with
+/// the same expression.
+pub(crate) fn continues(source: &str) -> bool {
+    open_groups(&uncommented(source)).is_ok_and(|open| !open.is_empty())
+}
+
+/// The groups `source` leaves open and where, failing at a closing
+/// bracket that doesn't match the last one open.
+fn open_groups(source: &str) -> Result<Vec<(usize, char)>, Error> {
+    let mut open = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for (pos, ch) in source.char_indices() {
+        if let Some(quoted) = quote {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == quoted {
+                quote = None;
+            }
+            continue;
+        }
+        let opening = match ch {
+            '"' | '\'' => {
+                quote = Some(ch);
+                continue;
+            }
+            '(' | '[' | '{' => {
+                open.push((pos, ch));
+                continue;
+            }
+            ')' => '(',
+            ']' => '[',
+            '}' => '{',
+            _ => continue,
+        };
+        match open.pop() {
+            Some((_, other)) if other == opening => {}
+            _ => return Err(Error::lex(format!("unmatched {}", ch), Span::new(pos, pos + 1))),
+        }
+    }
+    Ok(open)
+}
+
+/// Splits `text` at every top level `separator`. Each part comes with the
+/// offset where it starts.
+fn split_top_level(text: &str, separator: char) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (pos, ch) in scan_top_level(text) {
+        if ch == separator {
+            parts.push((start, &text[start..pos]));
+            start = pos + separator.len_utf8();
+        }
+    }
+    parts.push((start, &text[start..]));
+    parts
+}
+
+/// Reads the key of a map literal entry: a name, a number or a string.
+fn parse_key(text: &str) -> Option<Token> {
+    let text = text.trim();
+    let mut chars = text.char_indices().peekable();
+    match chars.peek() {
+        Some((_, 'a'..='z' | 'A'..='Z' | '_'))
+            if text.chars().all(|ch| ch.is_alphanumeric() || ch == '_') =>
+        {
+            Some(Token::String(text.to_string()))
+        }
+        Some((_, '0'..='9')) => match lex_number(&mut chars) {
+            number if chars.peek().is_none() => Some(number),
+            _ => None,
+        },
+        Some((_, '"')) => {
+            chars.next();
+            lex_string(&mut chars, 0)
+                .ok()
+                .filter(|_| chars.peek().is_none())
+                .map(Token::String)
+        }
+        _ => None,
+    }
+}
+
+/// Whether a `:` comes next, making the name before it a binding.
+fn starts_binding(chars: &Chars) -> bool {
+    chars.clone().map(|(_, ch)| ch).find(|ch| !ch.is_whitespace()) == Some(':')
+}
+
+/// The names `source` binds with `name:`, in operator bodies and map
+/// literals too, and where each is written, string literals left out.
+pub(crate) fn bindings(source: &str) -> Vec<(String, Span)> {
+    let source = uncommented(source);
+    let mut bound = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((pos, ch)) = chars.next() {
+        match ch {
+            '"' | '\'' => {
+                let mut escaped = false;
+                for (_, next) in chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                    } else if next == '\\' {
+                        escaped = true;
+                    } else if next == ch {
+                        break;
+                    }
+                }
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let mut name = ch.to_string();
+                let is_name = |&(_, next): &(usize, char)| next.is_alphanumeric() || next == '_';
+                while let Some((_, next)) = chars.next_if(is_name) {
+                    name.push(next);
+                }
+                let keyword = matches!(name.as_str(), "left" | "right" | "this" | "true" | "false");
+                if !keyword && starts_binding(&chars) {
+                    let span = Span::new(pos, pos + name.len());
+                    bound.push((name, span));
+                }
+            }
+            // A number binds nothing, letters written right after it neither.
+            _ if ch.is_alphanumeric() => {
+                while chars.next_if(|&(_, next)| next.is_alphanumeric()).is_some() {}
+            }
+            _ => {}
+        }
+    }
+    bound
+}
+
+/// The name in `name: {...}`, when the tokens so far end with `name:`.
+fn bound_name(tokens: &[Token]) -> Option<String> {
+    match tokens {
+        [.., Token::Variable(name, _), Token::Operator(colon, OperatorType::Infix)]
+            if colon == ":" =>
+        {
+            Some(name.clone())
+        }
+        _ => None,
+    }
+}
+
+/// The names used in an operator body, string literals left out. Names in
+/// nested operators only count with `nested_operators`; those in nested map
+/// literals always do.
+fn body_identifiers(body: &str, nested_operators: bool) -> Vec<String> {
+    let mut idents = Vec::new();
+    let mut ident = String::new();
+    let mut chars = body.char_indices().peekable();
+    while let Some((pos, ch)) = chars.next() {
+        if ch.is_alphanumeric() || ch == '_' {
+            ident.push(ch);
+            continue;
+        }
+        if !ident.is_empty() {
+            idents.push(std::mem::take(&mut ident));
+        }
+        match ch {
+            '"' | '\'' => {
+                let mut escaped = false;
+                for (_, next) in chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                    } else if next == '\\' {
+                        escaped = true;
+                    } else if next == ch {
+                        break;
+                    }
+                }
+            }
+            '{' => {
+                let inner = lex_brace_body(&mut chars, pos).unwrap_or_default();
+                if nested_operators || is_map_literal(&inner) {
+                    idents.extend(body_identifiers(&inner, nested_operators));
+                }
+            }
+            _ => {}
+        }
+    }
+    if !ident.is_empty() {
+        idents.push(ident);
+    }
+    idents
+}
+
+/// Whether a brace holds a map literal rather than an operator body: it is
+/// empty or opens with a `key:` entry, and uses none of the operator keywords
+/// outside nested groups.
+fn is_map_literal(body: &str) -> bool {
+    if body.trim().is_empty() {
+        return true;
+    }
+    let top = scan_top_level(body);
+    let mut ident = String::new();
+    let mut last_pos = None;
+    let mut idents = Vec::new();
+    for &(pos, ch) in &top {
+        let contiguous = last_pos.is_some_and(|last| last + 1 == pos);
+        if ch.is_alphanumeric() || ch == '_' {
+            if !contiguous && !ident.is_empty() {
+                idents.push(std::mem::take(&mut ident));
+            }
+            ident.push(ch);
+        } else if !ident.is_empty() {
+            idents.push(std::mem::take(&mut ident));
+        }
+        last_pos = Some(pos + ch.len_utf8() - 1);
+    }
+    idents.push(ident);
+    if idents
+        .iter()
+        .any(|ident| matches!(ident.as_str(), "left" | "right" | "this"))
+    {
+        return false;
+    }
+    let (_, first_entry) = split_top_level(body, ',')[0];
+    match split_top_level(first_entry, ':').as_slice() {
+        [(_, key), _, ..] => parse_key(key).is_some(),
+        _ => false,
+    }
+}
+
+/// Renders a string as a literal that the tokenizer reads back unchanged.
+pub(crate) fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\0"),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn type_name(token: &Token) -> &'static str {
+    match token {
+        Token::Number(_) => "number",
+        Token::String(_) => "string",
+        Token::List(_) => "list",
+        Token::Entry(_, _) => "entry",
+        Token::Variable(_, _) => "variable",
+        Token::Operator(_, _) | Token::Function(_, _) => "operator",
+        _ => "token",
+    }
+}
+
+fn truthy(token: &Token) -> bool {
+    match token {
+        Token::Number(num) => !num.is_zero(),
+        Token::String(s) => !s.is_empty(),
+        Token::List(list) => !list.is_empty(),
+        _ => true,
+    }
+}
+
+fn bool_token(value: bool) -> Token {
+    Token::Number(if value {
+        BigDecimal::one()
+    } else {
+        BigDecimal::zero()
+    })
+}
+
+fn mismatch(op: &str, left: &Token, right: &Token) -> Error {
+    Error::type_error(format!(
+        "cannot apply {} to {} and {}",
+        op,
+        type_name(left),
+        type_name(right)
+    ))
+}
+
+/// Pops an operand. The `[` mark that opens a list literal is not an
+/// operand, so it stays on the stack.
+fn pop(stack: &mut Vec<Token>) -> Result<Token, Error> {
+    match stack.pop() {
+        Some(Token::LeftBracket) => {
+            stack.push(Token::LeftBracket);
+            Err(Error::eval("missing operand"))
+        }
+        Some(token) => Ok(token),
+        None => Err(Error::eval("missing operand")),
+    }
+}
+
+/// Builds the list closed by a `]`: everything above the matching `[` mark.
+/// An entry keyed by the index it would get anyway is stored as a plain
+/// element, so `{0: "a", b: 1}` and `["a" ("b": 1)]` are the same list.
+fn collect_list(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+) -> Result<Token, Error> {
+    let mut items = Vec::new();
+    loop {
+        match stack.pop() {
+            Some(Token::LeftBracket) => break,
+            Some(variable @ Token::Variable(_, _)) => {
+                stack.push(variable);
+                items.push(pop_value(stack, context)?);
+            }
+            Some(token) => items.push(token),
+            None => return Err(Error::eval("unmatched ]")),
+        }
+    }
+    items.reverse();
+    let mut next_index = BigDecimal::zero();
+    for item in items.iter_mut() {
+        if let Token::Entry(key, value) = item {
+            if **key == Token::Number(next_index.clone()) {
+                *item = (**value).clone();
+            }
+        }
+        if !matches!(item, Token::Entry(_, _)) {
+            next_index += 1;
+        }
+    }
+    let list = Token::List(items);
+    context.budget.check(&list)?;
+    Ok(list)
+}
+
+/// Finds the value stored under `key`: an entry with that key or, for a
+/// number, the element at that position among the elements without a key.
+fn lookup(list: &[Token], key: &Token) -> Result<Token, Error> {
+    let keyed = list.iter().find_map(|item| match item {
+        Token::Entry(entry_key, value) if compare(entry_key, key).is_eq() => Some(value),
+        _ => None,
+    });
+    if let Some(value) = keyed {
+        return Ok((**value).clone());
+    }
+    let position = match key {
+        Token::Number(num) => to_count(num).ok(),
+        _ => None,
+    };
+    position
+        .and_then(|i| {
+            list.iter()
+                .filter(|item| !matches!(item, Token::Entry(_, _)))
+                .nth(i)
+        })
+        .cloned()
+        .ok_or_else(|| match key {
+            Token::Number(num) => Error::eval(format!("no entry for key {}", num)),
+            Token::String(s) => Error::eval(format!("no entry for key {}", quote(s))),
+            _ => Error::eval(format!("no entry for {} key", type_name(key))),
+        })
+}
+
+/// Writes `text` and a newline to the output of the run.
+fn print(context: &Context, text: &str) -> Result<(), Error> {
+    let mut output = context.output.borrow_mut();
+    writeln!(output, "{}", text).map_err(|error| Error::io(error.to_string()))
+}
+
+/// Pops an operand, replacing a variable by the value bound to it and
+/// running whatever was deferred in it.
+fn pop_value(stack: &mut Vec<Token>, context: &mut Context) -> Result<Token, Error> {
+    let value = pop_lazy(stack, context)?;
+    force(value, context)
+}
+
+/// Pops an operand like `pop_value`, but leaves the thunks inside a list
+/// alone: the dot only runs the elements it picks.
+fn pop_lazy(stack: &mut Vec<Token>, context: &mut Context) -> Result<Token, Error> {
+    match pop(stack)? {
+        Token::Variable(name, span) => match context.get(&name) {
+            Some(value) => Ok(value.clone()),
+            // An operator a brace defined, bound in an earlier expression.
+            None if context.bodies.contains_key(&name) => Ok(capture(&name, context)),
+            None => Err(undefined(&name, span, context)),
+        },
+        Token::Thunk(body) => execute(&body, context),
+        token => Ok(token),
+    }
+}
+
+/// The error for an unbound name, suggesting the visible variables and the
+/// operators spelled closest to it.
+fn undefined(name: &str, span: Span, context: &Context) -> Error {
+    let locals = context.frames.last().into_iter().flat_map(|frame| frame.keys());
+    let globals = context.frames.first().into_iter().flat_map(|frame| frame.keys());
+    let operators = context.prefix.keys().chain(context.infix.keys()).chain(context.postfix.keys());
+    let limit = (name.chars().count() + 1) / 3;
+    let mut close: Vec<(usize, &String)> = locals
+        .chain(globals)
+        .chain(operators)
+        .filter(|candidate| candidate.starts_with(|ch: char| ch.is_alphabetic() || ch == '_'))
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .collect();
+    close.sort();
+    close.dedup();
+    let names: Vec<&str> = close.iter().take(3).map(|(_, name)| name.as_str()).collect();
+    let message = match names.as_slice() {
+        [] => format!("undefined variable: {}", name),
+        [only] => format!("undefined variable: {}, did you mean {}?", name, only),
+        [first @ .., last] => format!(
+            "undefined variable: {}, did you mean {} or {}?",
+            name,
+            first.join(", "),
+            last
+        ),
+    };
+    Error::name(message, span)
+}
+
+/// The number of single-character insertions, deletions, substitutions and
+/// swaps of neighbours turning `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (rows[i - 1][j] + 1)
+                .min(row[j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Runs the thunks in a value, however deep in lists they sit.
+fn force(token: Token, context: &mut Context) -> Result<Token, Error> {
+    match token {
+        Token::Thunk(body) => execute(&body, context),
+        Token::List(items) => items
+            .into_iter()
+            .map(|item| force(item, context))
+            .collect::<Result<_, _>>()
+            .map(Token::List),
+        Token::Entry(key, value) => Ok(Token::Entry(key, Box::new(force(*value, context)?))),
+        token => Ok(token),
+    }
+}
+
+/// Pops the operands of an infix operator, returned as `(left, right)`.
+fn pop_operands(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+) -> Result<(Token, Token), Error> {
+    let right = pop_value(stack, context)?;
+    let left = pop_value(stack, context)?;
+    Ok((left, right))
+}
+
+/// Converts a number used as a count or an index into a `usize`.
+fn to_count(num: &BigDecimal) -> Result<usize, Error> {
+    num.is_integer()
+        .then(|| num.to_usize())
+        .flatten()
+        .ok_or_else(|| Error::eval(format!("expected a non-negative integer, got {}", num)))
+}
+
+fn op_infix_number<F>(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    symbol: &str,
+    op: F,
+) -> Result<(), Error>
+where
+    F: Fn(BigDecimal, BigDecimal) -> BigDecimal,
+{
+    match pop_operands(stack, context)? {
+        (Token::Number(left_num), Token::Number(right_num)) => {
+            stack.push(Token::Number(op(left_num, right_num)));
+            Ok(())
+        }
+        (left, right) => Err(mismatch(symbol, &left, &right)),
+    }
+}
+
+/// `left / right` or `left % right`, an error rather than a panic when
+/// `right` is zero.
+fn op_divide(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    symbol: &str,
+    op: fn(BigDecimal, BigDecimal) -> BigDecimal,
+) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::Number(_), Token::Number(right)) if right.is_zero() => {
+            Err(Error::eval(format!("division by zero in {}", symbol)))
+        }
+        (Token::Number(left), Token::Number(right)) => {
+            stack.push(Token::Number(op(left, right)));
+            Ok(())
+        }
+        (left, right) => Err(mismatch(symbol, &left, &right)),
+    }
+}
+
+/// Position of each kind of value in the total ordering: numbers sort before
+/// strings, strings before lists and lists before entries, then operators
+/// and braces, then the tokens that are only pieces of expressions.
+fn type_rank(token: &Token) -> u8 {
+    match token {
+        Token::Number(_) => 0,
+        Token::String(_) => 1,
+        Token::List(_) => 2,
+        Token::Entry(_, _) => 3,
+        Token::Operator(_, _) => 4,
+        Token::Function(_, _) => 5,
+        Token::Variable(_, _) => 6,
+        Token::Thunk(_) => 7,
+        Token::LeftParen => 8,
+        Token::RightParen => 9,
+        Token::LeftBracket => 10,
+        Token::RightBracket => 11,
+        Token::LeftRef => 12,
+        Token::RightRef => 13,
+        #[allow(deprecated)]
+        Token::LeftBrace => 14,
+        #[allow(deprecated)]
+        Token::RightBrace => 15,
+    }
+}
+
+/// Compares two sequences element by element, a prefix before the longer.
+fn compare_slices<T>(left: &[T], right: &[T], cmp: impl Fn(&T, &T) -> Ordering) -> Ordering {
+    left.iter()
+        .zip(right)
+        .map(|(l, r)| cmp(l, r))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| left.len().cmp(&right.len()))
+}
+
+/// Total ordering over values. Values of the same kind compare by content,
+/// lists and entries recursively; values of different kinds by `type_rank`.
+pub(crate) fn compare(left: &Token, right: &Token) -> Ordering {
+    match (left, right) {
+        (Token::Number(left_num), Token::Number(right_num)) => left_num.cmp(right_num),
+        (Token::String(left_str), Token::String(right_str)) => left_str.cmp(right_str),
+        (Token::List(left_list), Token::List(right_list))
+        | (Token::Thunk(left_list), Token::Thunk(right_list)) => {
+            compare_slices(left_list, right_list, compare)
+        }
+        (Token::Entry(left_key, left_value), Token::Entry(right_key, right_value)) => {
+            compare(left_key, right_key).then_with(|| compare(left_value, right_value))
+        }
+        (Token::Operator(left_op, left_type), Token::Operator(right_op, right_type)) => {
+            left_op.cmp(right_op).then_with(|| left_type.cmp(right_type))
+        }
+        // The same brace with different captures is a different value.
+        (Token::Function(left_op, left_captured), Token::Function(right_op, right_captured)) => {
+            left_op.cmp(right_op).then_with(|| {
+                compare_slices(left_captured, right_captured, |(l_name, l), (r_name, r)| {
+                    l_name.cmp(r_name).then_with(|| compare(l, r))
+                })
+            })
+        }
+        (Token::Variable(left_name, left_span), Token::Variable(right_name, right_span)) => {
+            let span = |span: &Span| (span.start, span.end);
+            left_name.cmp(right_name).then_with(|| span(left_span).cmp(&span(right_span)))
+        }
+        _ => type_rank(left).cmp(&type_rank(right)),
+    }
+}
+
+fn op_infix_compare<F>(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    op: F,
+) -> Result<(), Error>
+where
+    F: Fn(Ordering) -> bool,
+{
+    let (left, right) = pop_operands(stack, context)?;
+    stack.push(bool_token(op(compare(&left, &right))));
+    Ok(())
+}
+
+fn op_prefix_list<F>(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    symbol: &str,
+    op: F,
+) -> Result<(), Error>
+where
+    F: Fn(Vec<Token>) -> Vec<Token>,
+{
+    match pop_value(stack, context)? {
+        Token::List(list) => {
+            stack.push(Token::List(op(list)));
+            Ok(())
+        }
+        right => Err(Error::type_error(format!(
+            "cannot apply {} to {}",
+            symbol,
+            type_name(&right)
+        ))),
+    }
+}
+
+fn sorted(mut list: Vec<Token>) -> Vec<Token> {
+    list.sort_by(compare);
+    list
 }
-fn op_posfix_op(stack: &mut Vec<Token>, context: &mut HashMap<String, Token>) {
-    let left = stack.pop().unwrap();
-    context.insert(String::from("left"), left);
-    stack.push(Token::String(String::from("undefined")));
-    context.remove("left");
+
+/// Drops every element equal to an earlier one, keeping the first occurrence.
+fn deduplicated(list: Vec<Token>) -> Vec<Token> {
+    let mut unique: Vec<Token> = Vec::new();
+    for item in list {
+        if !unique.iter().any(|seen| compare(seen, &item).is_eq()) {
+            unique.push(item);
+        }
+    }
+    unique
+}
+
+/// `&&` and `||`: the right operand, deferred, only runs when the left one
+/// doesn't settle the result, that is when it isn't `settles`.
+fn op_short_circuit(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    settles: bool,
+) -> Result<(), Error> {
+    let right = pop(stack)?;
+    let left = pop_value(stack, context)?;
+    let result = if truthy(&left) == settles {
+        settles
+    } else {
+        stack.push(right);
+        truthy(&pop_value(stack, context)?)
+    };
+    stack.push(bool_token(result));
+    Ok(())
+}
+
+fn op_infix_bool<F>(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    op: F,
+) -> Result<(), Error>
+where
+    F: Fn(bool, bool) -> bool,
+{
+    let (left, right) = pop_operands(stack, context)?;
+    stack.push(bool_token(op(truthy(&left), truthy(&right))));
+    Ok(())
+}
+
+fn op_prefix_string<F>(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    symbol: &str,
+    op: F,
+) -> Result<(), Error>
+where
+    F: Fn(&str) -> String,
+{
+    match pop_value(stack, context)? {
+        Token::String(right_str) => {
+            stack.push(Token::String(op(&right_str)));
+            Ok(())
+        }
+        right => Err(Error::type_error(format!(
+            "cannot apply {} to {}",
+            symbol,
+            type_name(&right)
+        ))),
+    }
+}
+
+/// Applies a fallible `op` to a string operand, such as reading the file it
+/// names or parsing the text it holds.
+fn op_prefix_read<F>(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    symbol: &str,
+    op: F,
+) -> Result<(), Error>
+where
+    F: Fn(&str) -> Result<Token, Error>,
+{
+    match pop_value(stack, context)? {
+        Token::String(right_str) => {
+            stack.push(op(&right_str)?);
+            Ok(())
+        }
+        right => Err(Error::type_error(format!(
+            "cannot apply {} to {}",
+            symbol,
+            type_name(&right)
+        ))),
+    }
+}
+
+/// `@ path` reads a file as a string, if the file policy allows it.
+fn op_read_file(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    let fs = context.fs.clone();
+    op_prefix_read(stack, context, "@", |path| {
+        std::fs::read_to_string(fs.check_read(path)?)
+            .map(Token::String)
+            .map_err(|e| Error::io(format!("cannot read {}: {}", path, e)))
+    })
+}
+
+/// `value >> path` writes strings as they are and any other value as a
+/// literal, and yields the value.
+fn op_write_file(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    let (left, right) = pop_operands(stack, context)?;
+    let path = match right {
+        Token::String(path) => path,
+        right => return Err(mismatch(">>", &left, &right)),
+    };
+    context.fs.check_write(&path)?;
+    let content = match &left {
+        Token::String(s) => s.clone(),
+        value => format!("{:#}", value),
+    };
+    std::fs::write(&path, content).map_err(|e| Error::io(format!("cannot write {}: {}", path, e)))?;
+    stack.push(left);
+    Ok(())
+}
+
+fn op_add(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::Number(left_num), Token::Number(right_num)) => {
+            stack.push(Token::Number(left_num + right_num))
+        }
+        (Token::String(left_str), Token::String(right_str)) => {
+            stack.push(Token::String(left_str + &right_str))
+        }
+        (left, right) => return Err(mismatch("+", &left, &right)),
+    }
+    Ok(())
+}
+
+fn op_multiply(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::Number(left_num), Token::Number(right_num)) => {
+            stack.push(Token::Number(left_num * right_num))
+        }
+        (Token::String(s), Token::Number(times)) | (Token::Number(times), Token::String(s)) => {
+            let times = to_count(&times)?;
+            context.budget.length(s.len().saturating_mul(times))?;
+            stack.push(Token::String(s.repeat(times)))
+        }
+        (left, right) => return Err(mismatch("*", &left, &right)),
+    }
+    Ok(())
+}
+
+/// Looks up `index` in a sequence of `len` elements.
+fn checked_index(index: &Token, len: usize) -> Result<usize, Error> {
+    match index {
+        Token::Number(num) => {
+            let i = to_count(num)?;
+            if i < len {
+                Ok(i)
+            } else {
+                Err(Error::eval(format!(
+                    "index {} out of range for length {}",
+                    i, len
+                )))
+            }
+        }
+        other => Err(Error::type_error(format!(
+            "cannot index with {}",
+            type_name(other)
+        ))),
+    }
+}
+
+/// The dot operator: a key picks one element (or character) of the left
+/// operand, a list of keys picks several.
+fn op_index(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    let right = pop_value(stack, context)?;
+    let left = pop_lazy(stack, context)?;
+    let indexed = match (left, right) {
+        (Token::String(s), Token::List(indices)) => {
+            let chars: Vec<char> = s.chars().collect();
+            let mut picked = String::new();
+            for index in &indices {
+                picked.push(chars[checked_index(index, chars.len())?]);
+            }
+            Token::String(picked)
+        }
+        (Token::String(s), index) => {
+            let chars: Vec<char> = s.chars().collect();
+            Token::String(chars[checked_index(&index, chars.len())?].to_string())
+        }
+        (Token::List(list), Token::List(keys)) => {
+            let mut picked = Vec::new();
+            for key in &keys {
+                picked.push(force(lookup(&list, key)?, context)?);
+            }
+            Token::List(picked)
+        }
+        (Token::List(list), key) => force(lookup(&list, &key)?, context)?,
+        (Token::Function(symbol, captured), operand) => {
+            apply_function(symbol, &captured, operand, context)?
+        }
+        (left, right) => return Err(mismatch(".", &left, &right)),
+    };
+    stack.push(indexed);
+    Ok(())
+}
+
+/// `left ** right` for a whole `right`, by repeated squaring so the digits
+/// limit stops it before the number gets out of hand.
+fn op_power(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::Number(base), Token::Number(exponent)) => {
+            let mut exponent = to_count(&exponent)?;
+            let mut square = base;
+            let mut result = BigDecimal::one();
+            while exponent > 0 {
+                if exponent % 2 == 1 {
+                    result *= &square;
+                    context.budget.number(&result)?;
+                }
+                exponent /= 2;
+                if exponent > 0 {
+                    square = &square * &square;
+                    context.budget.number(&square)?;
+                }
+            }
+            stack.push(Token::Number(result));
+            Ok(())
+        }
+        (left, right) => Err(mismatch("**", &left, &right)),
+    }
+}
+
+/// `start .. end` is the list of integers from `start` up to, not including, `end`.
+fn op_range(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::Number(start), Token::Number(end)) => {
+            let (start, end) = (to_count(&start)?, to_count(&end)?);
+            context.budget.length(end.saturating_sub(start))?;
+            let range = (start..end)
+                .map(|i| Token::Number(BigDecimal::from_usize(i).unwrap_or_default()))
+                .collect();
+            stack.push(Token::List(range));
+            Ok(())
+        }
+        (left, right) => Err(mismatch("..", &left, &right)),
+    }
+}
+
+fn op_split(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::String(s), Token::String(separator)) => {
+            let parts = if separator.is_empty() {
+                s.chars().map(|ch| Token::String(ch.to_string())).collect()
+            } else {
+                s.split(separator.as_str())
+                    .map(|part| Token::String(part.to_string()))
+                    .collect()
+            };
+            stack.push(Token::List(parts));
+            Ok(())
+        }
+        (left, right) => Err(mismatch("split", &left, &right)),
+    }
+}
+
+fn op_join(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::List(list), Token::String(separator)) => {
+            let mut parts = Vec::new();
+            for item in list {
+                match item {
+                    Token::String(s) => parts.push(s),
+                    Token::Number(num) => parts.push(num.to_string()),
+                    other => {
+                        return Err(Error::type_error(format!(
+                            "cannot join a list holding a {}",
+                            type_name(&other)
+                        )))
+                    }
+                }
+            }
+            stack.push(Token::String(parts.join(&separator)));
+            Ok(())
+        }
+        (left, right) => Err(mismatch("join", &left, &right)),
+    }
+}
+
+/// `s replace (from, to)` replaces every occurrence of `from` in `s` by `to`.
+fn op_replace(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    match pop_operands(stack, context)? {
+        (Token::String(s), Token::List(pair)) => match pair.as_slice() {
+            [Token::String(from), Token::String(to)] => {
+                stack.push(Token::String(s.replace(from.as_str(), to)));
+                Ok(())
+            }
+            _ => Err(Error::type_error(
+                "replace expects a pair of strings on the right",
+            )),
+        },
+        (left, right) => Err(mismatch("replace", &left, &right)),
+    }
+}
+
+fn op_length(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    let length = match pop_value(stack, context)? {
+        Token::String(s) => s.chars().count(),
+        Token::List(list) => list.len(),
+        right => {
+            return Err(Error::type_error(format!(
+                "cannot apply len to {}",
+                type_name(&right)
+            )))
+        }
+    };
+    stack.push(Token::Number(BigDecimal::from_usize(length).unwrap_or_default()));
+    Ok(())
+}
+
+/// `assert condition` and `message assert condition` fail with an assertion
+/// error unless the condition holds, and give it back when it does.
+fn op_assert(
+    stack: &mut Vec<Token>,
+    context: &mut Context,
+    with_message: bool,
+) -> Result<(), Error> {
+    let condition = pop_value(stack, context)?;
+    let message = if with_message { Some(pop_value(stack, context)?) } else { None };
+    if truthy(&condition) {
+        stack.push(condition);
+        return Ok(());
+    }
+    Err(Error::assertion(match message {
+        Some(Token::String(message)) => message,
+        Some(message) => message.to_string(),
+        None => format!("got {}", condition),
+    }))
+}
+
+/// `kind expect_error expression` runs the expression, deferred, and checks
+/// that it fails with an error of that kind, `"eval"` or `"type"` say. It
+/// gives back the message of the error.
+fn op_expect_error(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    let expression = pop(stack)?;
+    let kind = match pop_value(stack, context)? {
+        Token::String(kind) => kind,
+        other => return Err(mismatch("expect_error", &other, &expression)),
+    };
+    stack.push(expression);
+    match pop_value(stack, context) {
+        Err(error) if error.kind.to_string() == kind => {
+            stack.push(Token::String(error.message));
+            Ok(())
+        }
+        Err(error) => Err(Error::assertion(format!("expected {} error, got {}", kind, error))),
+        Ok(value) => Err(Error::assertion(format!("expected {} error, got {}", kind, value))),
+    }
+}
+
+/// `name test {...}` files the brace as a test named `name`, for
+/// [`Machine::run_test`], and gives it back without running it.
+fn op_test(stack: &mut Vec<Token>, context: &mut Context) -> Result<(), Error> {
+    let body = pop_value(stack, context)?;
+    let name = pop_value(stack, context)?;
+    match (name, &body) {
+        (Token::String(name), Token::Function(_, _)) => {
+            context.tests.push((name, body.clone()));
+            stack.push(body);
+            Ok(())
+        }
+        (name, body) => Err(mismatch("test", &name, body)),
+    }
+}
+
+/// Operators registered from Rust run the closure the context holds for
+/// them instead.
+fn op_native(_: &mut Vec<Token>, _: &mut Context) -> Result<(), Error> {
+    Err(Error::eval("native operator not found"))
+}
+
+/// Brace operators run their compiled body from `execute`; their table
+/// entries only carry fixity and precedence.
+fn op_body(_: &mut Vec<Token>, _: &mut Context) -> Result<(), Error> {
+    Err(Error::eval("operator body not found"))
 }
-pub fn get_standard_operators() -> Vec<Operator> {
+
+pub(crate) fn get_standard_operators() -> Vec<Operator> {
     vec![
         Operator {
             symbol: ":".to_string(),
@@ -539,30 +2906,74 @@ pub fn get_standard_operators() -> Vec<Operator> {
             assoc: Assoc::Right,
             op_type: OperatorType::Infix,
             func: |stack, context| {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                match (left, right) {
-                    (Token::Variable(left_var), right) => {
+                // The value of an entry stays deferred until picked.
+                let right = pop(stack)?;
+                match pop(stack)? {
+                    Token::Variable(left_var, _) => {
+                        stack.push(right);
+                        let right = pop_value(stack, context)?;
                         context.insert(left_var, right.clone());
                         stack.push(right);
+                        Ok(())
+                    }
+                    key @ (Token::Number(_) | Token::String(_)) => {
+                        let right = match right {
+                            Token::Variable(_, _) => {
+                                stack.push(right);
+                                pop_value(stack, context)?
+                            }
+                            right => right,
+                        };
+                        stack.push(Token::Entry(Box::new(key), Box::new(right)));
+                        Ok(())
                     }
-                    _ => panic!("Tried assignment operation without a variable on the left side."),
+                    _ => Err(Error::eval(
+                        "Tried assignment operation without a variable on the left side.",
+                    )),
                 }
             },
         },
+        Operator {
+            symbol: "assert".to_string(),
+            precedence: 2,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| op_assert(stack, context, false),
+        },
+        Operator {
+            symbol: "assert".to_string(),
+            precedence: 2,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Infix,
+            func: |stack, context| op_assert(stack, context, true),
+        },
+        Operator {
+            symbol: "expect_error".to_string(),
+            precedence: 2,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Infix,
+            func: op_expect_error,
+        },
+        Operator {
+            symbol: "test".to_string(),
+            precedence: 2,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Infix,
+            func: op_test,
+        },
         Operator {
             symbol: "||".to_string(),
             precedence: 3,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| op_infix_bool(stack, context, |left, right| left || right),
+            func: |stack, context| op_short_circuit(stack, context, true),
         },
         Operator {
             symbol: "&&".to_string(),
             precedence: 4,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| op_infix_bool(stack, context, |left, right| left && right),
+            func: |stack, context| op_short_circuit(stack, context, false),
         },
         Operator {
             symbol: "|".to_string(),
@@ -590,208 +3001,304 @@ pub fn get_standard_operators() -> Vec<Operator> {
             precedence: 8,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| {
-                op_infix_number(stack, context, |left, right| {
-                    if left == right {
-                        BigDecimal::one()
-                    } else {
-                        BigDecimal::zero()
-                    }
-                })
-            },
+            func: |stack, context| op_infix_compare(stack, context, Ordering::is_eq),
         },
         Operator {
             symbol: "<>".to_string(),
             precedence: 8,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| {
-                op_infix_number(stack, context, |left, right| {
-                    if left != right {
-                        BigDecimal::one()
-                    } else {
-                        BigDecimal::zero()
-                    }
-                })
-            },
+            func: |stack, context| op_infix_compare(stack, context, Ordering::is_ne),
         },
         Operator {
             symbol: ">".to_string(),
             precedence: 9,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| {
-                op_infix_number(stack, context, |left, right| {
-                    if left > right {
-                        BigDecimal::one()
-                    } else {
-                        BigDecimal::zero()
-                    }
-                })
-            },
+            func: |stack, context| op_infix_compare(stack, context, Ordering::is_gt),
         },
         Operator {
             symbol: "<".to_string(),
             precedence: 9,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| {
-                op_infix_number(stack, context, |left, right| {
-                    if left < right {
-                        BigDecimal::one()
-                    } else {
-                        BigDecimal::zero()
-                    }
-                })
-            },
+            func: |stack, context| op_infix_compare(stack, context, Ordering::is_lt),
         },
         Operator {
             symbol: ">=".to_string(),
             precedence: 9,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
+            func: |stack, context| op_infix_compare(stack, context, Ordering::is_ge),
+        },
+        Operator {
+            symbol: "<=".to_string(),
+            precedence: 9,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: |stack, context| op_infix_compare(stack, context, Ordering::is_le),
+        },
+        Operator {
+            symbol: "+".to_string(),
+            precedence: 11,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: op_add,
+        },
+        Operator {
+            symbol: "-".to_string(),
+            precedence: 11,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
             func: |stack, context| {
-                op_infix_number(stack, context, |left, right| {
-                    if left >= right {
-                        BigDecimal::one()
-                    } else {
-                        BigDecimal::zero()
-                    }
-                })
+                op_infix_number(stack, context, "-", |left, right| left - right)
+            },
+        },
+        Operator {
+            symbol: "$".to_string(),
+            precedence: 11,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: |_, context| print(context, "$"),
+        },
+        Operator {
+            symbol: "*".to_string(),
+            precedence: 12,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: op_multiply,
+        },
+        Operator {
+            symbol: "/".to_string(),
+            precedence: 12,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: |stack, context| op_divide(stack, context, "/", |left, right| left / right),
+        },
+        Operator {
+            symbol: "%".to_string(),
+            precedence: 12,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: |stack, context| op_divide(stack, context, "%", |left, right| left % right),
+        },
+        Operator {
+            symbol: "**".to_string(),
+            precedence: 13,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Infix,
+            func: op_power,
+        },
+        Operator {
+            symbol: "-".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| match pop_value(stack, context)? {
+                Token::Number(right_num) => {
+                    stack.push(Token::Number(-right_num));
+                    Ok(())
+                }
+                right => Err(Error::type_error(format!(
+                    "cannot apply - to {}",
+                    type_name(&right)
+                ))),
+            },
+        },
+        Operator {
+            symbol: "+".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| match pop_value(stack, context)? {
+                Token::Number(right_num) => {
+                    stack.push(Token::Number(right_num));
+                    Ok(())
+                }
+                right => Err(Error::type_error(format!(
+                    "cannot apply + to {}",
+                    type_name(&right)
+                ))),
+            },
+        },
+        Operator {
+            symbol: "~".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |_, context| print(context, "~"),
+        },
+        Operator {
+            symbol: "len".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: op_length,
+        },
+        Operator {
+            symbol: "trim".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| {
+                op_prefix_string(stack, context, "trim", |s| s.trim().to_string())
             },
         },
         Operator {
-            symbol: "<=".to_string(),
-            precedence: 9,
-            assoc: Assoc::Left,
-            op_type: OperatorType::Infix,
+            symbol: "upper".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| op_prefix_string(stack, context, "upper", str::to_uppercase),
+        },
+        Operator {
+            symbol: "lower".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| op_prefix_string(stack, context, "lower", str::to_lowercase),
+        },
+        Operator {
+            symbol: "sort".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| op_prefix_list(stack, context, "sort", sorted),
+        },
+        Operator {
+            symbol: "uniq".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| op_prefix_list(stack, context, "uniq", deduplicated),
+        },
+        Operator {
+            symbol: "@".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: op_read_file,
+        },
+        Operator {
+            symbol: "fromjson".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| op_prefix_read(stack, context, "fromjson", json::from_json),
+        },
+        Operator {
+            symbol: "fromcsv".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| op_prefix_read(stack, context, "fromcsv", csv::from_csv),
+        },
+        Operator {
+            symbol: "tojson".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
             func: |stack, context| {
-                op_infix_number(stack, context, |left, right| {
-                    if left <= right {
-                        BigDecimal::one()
-                    } else {
-                        BigDecimal::zero()
-                    }
-                })
+                let value = pop_value(stack, context)?;
+                stack.push(Token::String(json::to_json(&value)));
+                Ok(())
             },
         },
         Operator {
-            symbol: "+".to_string(),
-            precedence: 11,
-            assoc: Assoc::Left,
-            op_type: OperatorType::Infix,
-            func: |stack, context| op_infix_number(stack, context, |left, right| left + right),
+            symbol: "tocsv".to_string(),
+            precedence: 14,
+            assoc: Assoc::Right,
+            op_type: OperatorType::Prefix,
+            func: |stack, context| {
+                let value = pop_value(stack, context)?;
+                stack.push(Token::String(csv::to_csv(&value)?));
+                Ok(())
+            },
         },
         Operator {
-            symbol: "-".to_string(),
-            precedence: 11,
+            symbol: ">>".to_string(),
+            precedence: 2,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| op_infix_number(stack, context, |left, right| left - right),
+            func: op_write_file,
         },
+        // `this` is the brace operator being run, called in any position.
         Operator {
-            symbol: "$".to_string(),
-            precedence: 11,
+            symbol: "this".to_string(),
+            precedence: 20,
             assoc: Assoc::Left,
-            op_type: OperatorType::Infix,
-            func: |_, _| println!("$"),
+            op_type: OperatorType::Prefix,
+            func: op_body,
         },
         Operator {
-            symbol: "*".to_string(),
-            precedence: 12,
+            symbol: "this".to_string(),
+            precedence: 20,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, context| op_infix_number(stack, context, |left, right| left * right),
+            func: op_body,
         },
         Operator {
-            symbol: "/".to_string(),
-            precedence: 12,
+            symbol: "this".to_string(),
+            precedence: 20,
             assoc: Assoc::Left,
-            op_type: OperatorType::Infix,
-            func: |stack, context| op_infix_number(stack, context, |left, right| left / right),
+            op_type: OperatorType::Postfix,
+            func: op_body,
         },
+        // Stack words, for programs written in RPN.
         Operator {
-            symbol: "%".to_string(),
-            precedence: 12,
+            symbol: "!".to_string(),
+            precedence: 16,
             assoc: Assoc::Left,
-            op_type: OperatorType::Infix,
-            func: |stack, context| op_infix_number(stack, context, |left, right| left % right),
-        },
-        Operator {
-            symbol: "**".to_string(),
-            precedence: 13,
-            assoc: Assoc::Right,
-            op_type: OperatorType::Infix,
-            func: |stack, context| {
-                op_infix_number(stack, context, |left, right| {
-                    let mut result = BigDecimal::from_i32(1).expect("TODO!");
-                    for _ in 0..(right.to_u128().expect("TODO!")) {
-                        result *= &left;
-                    }
-                    result
-                });
+            op_type: OperatorType::Postfix,
+            func: |stack, _| {
+                let top = pop(stack)?;
+                pop(stack)?;
+                stack.push(top);
+                Ok(())
             },
         },
         Operator {
-            symbol: "-".to_string(),
-            precedence: 14,
-            assoc: Assoc::Right,
-            op_type: OperatorType::Prefix,
-            func: |stack, _context| {
-                let right = stack.pop().unwrap();
-                match right {
-                    Token::Number(right_num) => stack.push(Token::Number(-right_num)),
-                    Token::String(right_str) => stack.push(Token::Number(
-                        BigDecimal::from_usize(right_str.len()).unwrap().neg(),
-                    )),
-                    Token::List(right_list) => stack.push(Token::Number(
-                        BigDecimal::from_usize(right_list.len()).unwrap().neg(),
-                    )),
-                    _ => todo!(),
-                }
+            symbol: "dup".to_string(),
+            precedence: 16,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Postfix,
+            func: |stack, _| {
+                let top = pop(stack)?;
+                stack.push(top.clone());
+                stack.push(top);
+                Ok(())
             },
         },
         Operator {
-            symbol: "+".to_string(),
-            precedence: 14,
-            assoc: Assoc::Right,
-            op_type: OperatorType::Prefix,
-            func: |stack, _context| {
-                let right = stack.pop().unwrap();
-                match right {
-                    Token::Number(right_num) => stack.push(Token::Number(right_num)),
-                    Token::String(right_str) => stack.push(Token::Number(
-                        BigDecimal::from_usize(right_str.len()).unwrap(),
-                    )),
-                    Token::List(right_list) => stack.push(Token::Number(
-                        BigDecimal::from_usize(right_list.len()).unwrap(),
-                    )),
-                    _ => todo!(),
-                }
+            symbol: "swap".to_string(),
+            precedence: 16,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Postfix,
+            func: |stack, _| {
+                let top = pop(stack)?;
+                let below = pop(stack)?;
+                stack.push(top);
+                stack.push(below);
+                Ok(())
             },
         },
-        Operator {
-            symbol: "~".to_string(),
-            precedence: 14,
-            assoc: Assoc::Right,
-            op_type: OperatorType::Prefix,
-            func: |_, _| println!("~"),
-        },
         Operator {
             symbol: "?".to_string(),
             precedence: 16,
             assoc: Assoc::Left,
             op_type: OperatorType::Postfix,
-            func: |_, _| println!("?"),
+            func: |_, context| print(context, "?"),
         },
+        // The parser turns chains of `,` into list literals; the operator
+        // itself only runs for RPN written by hand.
         Operator {
             symbol: ",".to_string(),
             precedence: 16,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |stack, _context| {
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
+            func: |stack, context| {
+                let right = pop_value(stack, context)?;
+                let left = pop_value(stack, context)?;
                 match left {
                     Token::List(mut left_list) => {
                         left_list.push(right);
@@ -799,6 +3306,7 @@ pub fn get_standard_operators() -> Vec<Operator> {
                     }
                     _ => stack.push(Token::List(vec![left, right])),
                 }
+                Ok(())
             },
         },
         Operator {
@@ -806,14 +3314,42 @@ pub fn get_standard_operators() -> Vec<Operator> {
             precedence: 16,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |_, _| println!(";"),
+            func: |_, context| print(context, ";"),
         },
         Operator {
             symbol: ".".to_string(),
             precedence: 16,
             assoc: Assoc::Left,
             op_type: OperatorType::Infix,
-            func: |_, _| println!("."),
+            func: op_index,
+        },
+        Operator {
+            symbol: "split".to_string(),
+            precedence: 16,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: op_split,
+        },
+        Operator {
+            symbol: "join".to_string(),
+            precedence: 16,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: op_join,
+        },
+        Operator {
+            symbol: "replace".to_string(),
+            precedence: 16,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: op_replace,
+        },
+        Operator {
+            symbol: "..".to_string(),
+            precedence: 17,
+            assoc: Assoc::Left,
+            op_type: OperatorType::Infix,
+            func: op_range,
         },
         // Add more standard operators here
     ]
@@ -835,6 +3371,12 @@ fn get_prefix_operator<'a>(symbol: &str, operators: &'a [Operator]) -> Option<&'
         .find(|op| op.symbol == symbol && op.op_type == OperatorType::Prefix)
 }
 
+fn get_postfix_operator<'a>(symbol: &str, operators: &'a [Operator]) -> Option<&'a Operator> {
+    operators
+        .iter()
+        .find(|op| op.symbol == symbol && op.op_type == OperatorType::Postfix)
+}
+
 
 fn precedence(op: &str, operators: &[Operator]) -> usize {
     if let Some(operator) = get_operator(op, operators) {
@@ -847,6 +3389,7 @@ fn precedence(op: &str, operators: &[Operator]) -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::ErrorKind;
 
     fn test_expression(expression: &str, expected: &str) {
         let mut machine = Machine::new();
@@ -862,7 +3405,7 @@ mod tests {
         test_expression("5 - 2", "3");
         test_expression("2 * 5", "10");
         test_expression("5 / 2", "2.5");
-        test_expression("0, 0 + 5", "7");
+        test_expression("len (0, 0) + 5", "7");
         test_expression("5 % 2", "1");
         test_expression("(1 + 2) * 3", "9");
         test_expression("-1", "-1");
@@ -872,19 +3415,602 @@ mod tests {
         test_expression("+1", "1");
         test_expression("+1+1-1", "1");
         test_expression("(1 - 1) + +1", "1");
-        test_expression("\"test\" - +1", "3");
+        test_expression("len \"test\" - +1", "3");
         test_expression("2 ** 3", "8");
     }
+
+    #[test]
+    fn test_division_by_zero() {
+        let error = Machine::new().run("1 / (1 - 1)").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Eval);
+        assert_eq!(error.message, "division by zero in /");
+        assert_eq!(Machine::new().run("1 % 0").unwrap_err().message, "division by zero in %");
+    }
+
     #[test]
     fn test_var_evaluation() {
         test_expression("x: 1 - 1", "0");
         test_expression("(x: 1) - x", "0");
     }
 
+    #[test]
+    fn test_operator_lexing() {
+        test_expression("1+-1", "0");
+        test_expression("2*-3", "-6");
+        test_expression("2**3", "8");
+        test_expression("1<=1", "1");
+        test_expression("-(1-2)", "1");
+        test_expression("(x:1)-x", "0");
+        test_lex_error("1 ` 2", "unknown operator `");
+        test_lex_error("1 ~ 2", "unknown infix operator ~");
+        let mut machine = Machine::new();
+        machine.register_number("+++", OperatorType::Infix, 12, |operands| {
+            Ok(&operands[0] + &operands[1] + BigDecimal::from(1))
+        });
+        assert_eq!(machine.run("1+++2").unwrap(), number(4));
+        assert_eq!(machine.run("1++2").unwrap(), number(3));
+        assert_eq!(machine.to_rpn("1+++-2").unwrap(), "1 2 u- +++");
+    }
+
     #[test]
     fn test_bool_evaluation() {
         test_expression("3 || 0", "1");
         test_expression("(1 - 1) || 0", "0");
         test_expression("0 && 0", "0");
     }
+
+    fn test_string(expression: &str, expected: &str) {
+        let mut machine = Machine::new();
+        let result = machine.run(expression).unwrap();
+        assert_eq!(result, Token::String(expected.to_string()));
+    }
+
+    fn test_lex_error(expression: &str, message: &str) {
+        let mut machine = Machine::new();
+        let error = machine.run(expression).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Lex);
+        assert_eq!(error.message, message);
+    }
+
+    #[test]
+    fn test_string_literals() {
+        test_string(r#""a \"quoted\" word""#, "a \"quoted\" word");
+        test_string(r#""tab\tnew\nline\\""#, "tab\tnew\nline\\");
+        test_string(r#""\u{48}\u{e9}\u{1F600}""#, "H\u{e9}\u{1F600}");
+        test_string("\"two\nlines\"", "two\nlines");
+        test_string("'a'", "a");
+        test_string(r"'\''", "'");
+        test_string(r#"r"C:\path\n""#, "C:\\path\\n");
+        test_string(r##"r#"say "hi""#"##, "say \"hi\"");
+    }
+
+    #[test]
+    fn test_unterminated_literals() {
+        test_lex_error("\"abc", "unterminated string literal");
+        test_lex_error("'a", "unterminated character literal");
+        test_lex_error("''", "empty character literal");
+        test_lex_error("'ab'", "character literal must hold exactly one character");
+        test_lex_error(r#""\q""#, "unknown escape sequence: \\q");
+        test_lex_error(r#""\u{110000}""#, "invalid unicode escape: \\u{110000}");
+        test_lex_error(r##"r#"abc"##, "unterminated raw string literal");
+    }
+
+    #[test]
+    fn test_string_operations() {
+        test_string("\"ab\" + \"cd\"", "abcd");
+        test_string("\"ab\" * 3", "ababab");
+        test_string("2 * \"-\"", "--");
+        test_string("\"héllo\" . 1", "é");
+        test_string("\"hello\" . 1..4", "ell");
+        test_string("\"hello\" . (4, 0)", "oh");
+        test_string("\"a,b,c\" split \",\" join \"-\"", "a-b-c");
+        test_string("trim \"  padded \"", "padded");
+        test_string("upper \"MiXed\" + lower \"MiXed\"", "MIXEDmixed");
+        test_string("\"banana\" replace (\"an\", \"o\")", "booa");
+        test_string("(1, 2, 3) join \", \"", "1, 2, 3");
+        test_expression("len \"héllo\"", "5");
+        test_expression("len (\"a b c\" split \" \")", "3");
+        test_expression("(10, 20, 30) . 2", "30");
+    }
+
+    #[test]
+    fn test_string_comparison() {
+        test_expression("\"abc\" = \"xyz\"", "0");
+        test_expression("\"abc\" = \"abc\"", "1");
+        test_expression("\"abc\" <> \"abd\"", "1");
+        test_expression("\"abc\" < \"abd\"", "1");
+        test_expression("\"b\" > \"abc\"", "1");
+        test_expression("\"\" || \"x\"", "1");
+    }
+
+    #[test]
+    fn test_structural_equality() {
+        test_expression("(1, 2) = (3, 4)", "0");
+        test_expression("(1, 2) = (1, 2)", "1");
+        test_expression("(1, 2) = (1, 2, 3)", "0");
+        test_expression("(1, 2) <> (2, 1)", "1");
+        test_expression("(\"a\", 1.0) = (\"a\", 1)", "1");
+        test_expression("(1: \"a\") = (1: \"a\")", "1");
+        test_expression("(1: \"a\") = (2: \"a\")", "0");
+        test_expression("(\"k\": (1, 2)) = (\"k\": (1, 3))", "0");
+        test_expression("1 = \"1\"", "0");
+    }
+
+    #[test]
+    fn test_total_ordering() {
+        test_expression("(1, 2) < (1, 3)", "1");
+        test_expression("(1, 2) < (1, 2, 0)", "1");
+        test_expression("(2) > (1, 9)", "0");
+        test_expression("99 < \"a\"", "1");
+        test_expression("\"z\" < (0, 0)", "1");
+        test_expression("(1: 5) >= (1: 5)", "1");
+        test_expression("sort (3, \"b\", 1, \"a\", 2) = (1, 2, 3, \"a\", \"b\")", "1");
+        test_expression("uniq (2, 1, 2, 3, 1) = (2, 1, 3)", "1");
+        test_expression("len uniq (\"x\", \"x\", 1, 1.00)", "2");
+    }
+
+    #[test]
+    fn test_ordering_of_operators() {
+        let operator = Token::Operator("f".to_string(), OperatorType::Prefix);
+        let function = Token::Function("f".to_string(), Rc::default());
+        assert_eq!(compare(&operator, &function), Ordering::Less);
+        let infix = Token::Operator("f".to_string(), OperatorType::Infix);
+        assert_eq!(compare(&operator, &infix), Ordering::Less);
+        let captured = Token::Function("f".to_string(), Rc::from([("n".to_string(), number(1))]));
+        assert_eq!(compare(&function, &captured), Ordering::Less);
+        assert_eq!(compare(&captured, &captured.clone()), Ordering::Equal);
+        test_expression("[(f: {{right + left}}) (len uniq ((f 1), (f 2), (f 1)))] . 1", "2");
+    }
+
+    fn number(value: i32) -> Token {
+        Token::Number(BigDecimal::from(value))
+    }
+
+    fn entry(key: Token, value: Token) -> Token {
+        Token::Entry(Box::new(key), Box::new(value))
+    }
+
+    fn test_value(expression: &str, expected: Token) {
+        let mut machine = Machine::new();
+        assert_eq!(machine.run(expression).unwrap(), expected, "{}", expression);
+    }
+
+    #[test]
+    fn test_bracket_literals() {
+        test_value("[1 2 3]", Token::List(vec![number(1), number(2), number(3)]));
+        test_value("[]", Token::List(vec![]));
+        test_value(
+            "[1 [2 3] []]",
+            Token::List(vec![
+                number(1),
+                Token::List(vec![number(2), number(3)]),
+                Token::List(vec![]),
+            ]),
+        );
+        test_value("[1 + 2 (3 * 2)]", Token::List(vec![number(3), number(6)]));
+        test_value("[1 -2 - 3]", Token::List(vec![number(1), number(-5)]));
+        test_value("[-1 (2 -3)]", Token::List(vec![number(-1), number(-1)]));
+        test_value(
+            "[\"a\" 'b' 2]",
+            Token::List(vec![
+                Token::String("a".to_string()),
+                Token::String("b".to_string()),
+                number(2),
+            ]),
+        );
+        test_expression("[10 20 30] . 1", "20");
+    }
+
+    #[test]
+    fn test_nested_lists() {
+        let list = |items: &[i32]| Token::List(items.iter().map(|&i| number(i)).collect());
+        test_value("1, 2, 3", list(&[1, 2, 3]));
+        test_value(
+            "1, 1, (2, 3)",
+            Token::List(vec![number(1), number(1), list(&[2, 3])]),
+        );
+        test_value("(1, 2), 3", Token::List(vec![list(&[1, 2]), number(3)]));
+        test_value(
+            "(1, 2), (3, 4)",
+            Token::List(vec![list(&[1, 2]), list(&[3, 4])]),
+        );
+        test_value(
+            "1, (2, (3, (4, 5)))",
+            Token::List(vec![
+                number(1),
+                Token::List(vec![
+                    number(2),
+                    Token::List(vec![number(3), list(&[4, 5])]),
+                ]),
+            ]),
+        );
+        test_value("[1 2], 3", Token::List(vec![list(&[1, 2]), number(3)]));
+        test_value("(x: (1, 2)) , x", Token::List(vec![list(&[1, 2]), list(&[1, 2])]));
+        test_expression("1, 1, (2, 3) = [1 1 [2 3]]", "1");
+        test_expression("len (1, 1, (2, 3))", "3");
+    }
+
+    #[test]
+    fn test_map_literals() {
+        test_value(
+            "{a: 3, 0: 2, 1: 1}",
+            Token::List(vec![
+                entry(Token::String("a".to_string()), number(3)),
+                number(2),
+                number(1),
+            ]),
+        );
+        test_value("{}", Token::List(vec![]));
+        test_value(
+            "{\"b c\": [1 2], 5: {x: 1}}",
+            Token::List(vec![
+                entry(
+                    Token::String("b c".to_string()),
+                    Token::List(vec![number(1), number(2)]),
+                ),
+                entry(
+                    number(5),
+                    Token::List(vec![entry(Token::String("x".to_string()), number(1))]),
+                ),
+            ]),
+        );
+        test_expression("{a: 1 + 1, b: 5} . \"a\"", "2");
+        test_expression("{a: 3, 0: 2} . 0", "2");
+        test_expression("{0: 5, 1: 6} = [5 6]", "1");
+        test_expression("{a: 1, b: 2} . (\"b\", \"a\") = [2 1]", "1");
+    }
+
+    #[test]
+    fn test_literal_errors() {
+        let mut machine = Machine::new();
+        assert_eq!(machine.run("{a: 1").unwrap_err().message, "unclosed {");
+        assert_eq!(machine.run("1 }").unwrap_err().message, "unmatched }");
+        assert_eq!(
+            machine.run("{a: }").unwrap_err().message,
+            "missing value in map literal"
+        );
+        assert_eq!(machine.run("[1 2").unwrap_err().message, "unclosed [");
+        assert_eq!(
+            machine.run("{a: 1} . \"b\"").unwrap_err().message,
+            "no entry for key \"b\""
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let display = |expression: &str| Machine::new().run(expression).unwrap().to_string();
+        assert_eq!(display("1, 2, 3"), "[1 2 3]");
+        assert_eq!(display("1, 1, (2, 3)"), "[1 1 [2 3]]");
+        assert_eq!(display("{a: 3, 0: 2, 1: 1}"), "{a: 3, 0: 2, 1: 1}");
+        assert_eq!(display("[\"tab\\t\" [] {}]"), "[\"tab\\t\" [] []]");
+        assert_eq!(display("{\"two words\": [1 {b: 2}]}"), "{\"two words\": [1 {b: 2}]}");
+        assert_eq!(display("\"k\": 1"), "\"k\": 1");
+        assert_eq!(display("true, false"), "[1 0]");
+        assert_eq!(display("0.0000000001 * 1"), "0.0000000001");
+        assert_eq!(display("10 ** 20"), "100000000000000000000");
+        assert_eq!(display("-2.50"), "-2.50");
+    }
+
+    #[test]
+    fn test_display_truncates_long_lists() {
+        let long = Machine::new().run("0 .. 250").unwrap();
+        let short = long.to_string();
+        assert!(short.starts_with("[0 1 2 "));
+        assert!(short.ends_with(" 99 ... (150 more)]"));
+        assert_eq!(format!("{:#}", long).split(' ').count(), 250);
+    }
+
+    #[test]
+    fn test_display_reads_back() {
+        let expressions = [
+            "1, (2, (3, \"x\")), []",
+            "{a: 3, 0: 2, 1: 1}",
+            "{\"k\": [1 2], 7: {x: \"q\\\"uote\"}, 0: 1}",
+            "\"line\\nbreak\", 'c', r\"raw\\\"",
+            "(1 / 8), (-0.5), (1 / 10000000000), [-1 -2]",
+        ];
+        for expression in expressions {
+            let mut machine = Machine::new();
+            let value = machine.run(expression).unwrap();
+            let printed = format!("{:#}", value);
+            assert_eq!(machine.run(&printed).unwrap(), value, "{}", printed);
+        }
+    }
+
+    #[test]
+    fn test_string_type_errors() {
+        let mut machine = Machine::new();
+        let failing = ["\"a\" + 1", "\"abc\" - \"b\"", "-\"abc\"", "\"a\" * -1", "\"abc\" . 3"];
+        for expression in failing {
+            assert!(machine.run(expression).is_err(), "{} should fail", expression);
+        }
+        let error = machine.run("\"a\" + 1").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+        assert_eq!(error.message, "cannot apply + to string and number");
+    }
+
+    #[test]
+    fn test_data_files() {
+        let file = std::env::temp_dir().join(format!("handrit-{}.csv", std::process::id()));
+        let path = quote(file.to_str().unwrap());
+        let mut machine = Machine::new();
+        machine.define("rows", json::from_json(r#"[{"a": 1.50}, {"a": 2}]"#).unwrap());
+        assert_eq!(
+            machine.run(&format!("(tocsv rows) >> {}", path)).unwrap(),
+            Token::String("a\n1.50\n2\n".to_string())
+        );
+        let read_back = machine.run(&format!("(fromcsv @ {}) = rows", path));
+        std::fs::remove_file(file).unwrap();
+        assert_eq!(read_back.unwrap(), number(1));
+        test_string("tojson {k: [1 \"v\"]}", "{\"k\":[1,\"v\"]}");
+        assert_eq!(machine.run("@ 1").unwrap_err().kind, ErrorKind::Type);
+        assert_eq!(machine.run("@ \"/no/such/file\"").unwrap_err().kind, ErrorKind::Io);
+        assert_eq!(machine.run("fromjson \"[1,\"").unwrap_err().kind, ErrorKind::Data);
+    }
+
+    #[test]
+    fn test_rpn_round_trip() {
+        let expressions = [
+            "1 - -2",
+            "-(1, 2) . 0 + +3",
+            "2 ** 3 ** 2 % 7",
+            "[1 -2 [\"a b\" 'c'] {a: 2, 0: 1}]",
+            "x: 3 * 2",
+            "len \"abc\" + len (1, 2)",
+            "\"a,b\" split \",\" join \"-\"",
+            "sort (3, 1, 2) . (0 .. 2)",
+            "true && (1 < 2) || false",
+            "-.5 * r\"x\\y\" = \"\"",
+            "0 && (1 / 1) || 2 > 1",
+            "(1: \"a\", 0: (\"b\" * 2)) . 0",
+        ];
+        for expression in expressions {
+            let mut machine = Machine::new();
+            let rpn = machine.to_rpn(expression).unwrap();
+            assert_eq!(
+                machine.run_rpn(&rpn),
+                Machine::new().run(expression),
+                "{} => {}",
+                expression,
+                rpn
+            );
+        }
+    }
+
+    #[test]
+    fn test_lazy_operands() {
+        test_expression("0 && nope", "0");
+        test_expression("1 || nope", "1");
+        test_expression("1 && (2 > 1)", "1");
+        test_expression("(true: 1, false: 2) . 0", "2");
+        test_string("{1: \"a\", 0: nope} . 1", "a");
+        test_string("(\"yes\": \"y\", \"no\": (nope + 1)) . \"yes\"", "y");
+        test_value(
+            "{a: 1 + 2, b: (1, 2)}",
+            Token::List(vec![
+                entry(Token::String("a".to_string()), number(3)),
+                entry(Token::String("b".to_string()), Token::List(vec![number(1), number(2)])),
+            ]),
+        );
+        let mut machine = Machine::new();
+        for failing in ["1 && nope", "{a: nope} . \"a\"", "{a: nope}", "right + 1"] {
+            assert!(machine.run(failing).is_err(), "{} should fail", failing);
+        }
+    }
+
+    #[test]
+    fn test_brace_operators() {
+        let mut machine = Machine::new();
+        machine
+            .run("fact: {(true: 1, false: (right * (this (right - 1)))) . (right <= 1)}")
+            .unwrap();
+        assert_eq!(machine.run("fact 5").unwrap(), number(120));
+        assert_eq!(machine.run("fact 1 + fact 3").unwrap(), number(7));
+        machine.run("isub: {right - left}").unwrap();
+        assert_eq!(machine.run("1 isub 0").unwrap(), number(-1));
+        machine.run("squared: {left * left}").unwrap();
+        assert_eq!(machine.run("3 squared").unwrap(), number(9));
+        assert_eq!(machine.run("3 squared - 1").unwrap(), number(8));
+        assert_eq!(machine.run("2 isub (1 isub 5)").unwrap(), number(2));
+        assert!(machine.run("this 1").is_err());
+        machine.run("isub: {left - right}").unwrap();
+        assert_eq!(machine.run("1 isub 0").unwrap(), number(1));
+        let body = "{(true: 1, false: (right * (this (right - 1)))) . (right <= 1)}";
+        let rpn = machine.to_rpn(&format!("fact: {}", body)).unwrap();
+        let function = Token::Function(body.to_string(), Rc::default());
+        assert_eq!(machine.run_rpn(&rpn).unwrap(), function);
+        assert_eq!(machine.run_rpn("4 fact").unwrap(), number(24));
+        let mut fresh = Machine::new();
+        assert_eq!(fresh.run_rpn("double { right * 2 } : 3 double").unwrap(), number(6));
+    }
+
+    #[test]
+    fn test_closures() {
+        let mut machine = Machine::new();
+        machine.run("adder: {{right + left}}").unwrap();
+        assert_eq!(machine.run("(adder 2) . 5").unwrap(), number(7));
+        machine.run("outer: {{left + right} . 10}").unwrap();
+        assert_eq!(machine.run("outer 5").unwrap(), number(15));
+        machine.run("pair: {{left, right}}").unwrap();
+        assert_eq!(
+            machine.run("(pair 1) . 2").unwrap(),
+            Token::List(vec![number(2), number(1)])
+        );
+        let snapshot = "[(n: 10) (f: {right + n}) (n: 1) (f . 5) (f 5)] . (3, 4)";
+        assert_eq!(machine.run(snapshot).unwrap(), Token::List(vec![number(15), number(15)]));
+        assert_eq!(machine.run("{right * 2} . 4").unwrap(), number(8));
+        let error = machine.run("{left + right} . 1").unwrap_err();
+        assert_eq!(error.message, "{left + right} needs a right operand");
+    }
+
+    #[test]
+    fn test_named_braces_as_values() {
+        let mut machine = Machine::new();
+        machine.run("twice: {right * 2}").unwrap();
+        assert_eq!(machine.run("twice . 4").unwrap(), number(8));
+        let snapshot = "[(n: 10) (f: {right + n}) (n: 1) (f . 5)] . 3";
+        assert_eq!(machine.run(snapshot).unwrap(), number(15));
+    }
+
+    #[test]
+    fn test_shared_captures() {
+        // Each call captures the last one's brace twice, shared rather than copied.
+        let error = Machine::new().run("w: {(w: {right}) (w {w right})} (w 1)").unwrap_err();
+        assert_eq!(error.message, "more than 100 nested operator calls");
+    }
+
+    #[test]
+    fn test_local_scopes() {
+        let mut machine = Machine::new();
+        machine.run("setx: {(x: right) + x}").unwrap();
+        assert_eq!(
+            machine.run("[(x: 1) (setx 5) x]").unwrap(),
+            Token::List(vec![number(1), number(10), number(1)])
+        );
+        machine.run("id: {right}").unwrap();
+        machine.run("keep: {[(id 1) right] . 1}").unwrap();
+        assert_eq!(machine.run("keep 7").unwrap(), number(7));
+        machine.run("inner: {right + y}").unwrap();
+        machine.run("outer: {[(y: 5) (inner right)] . 1}").unwrap();
+        let error = machine.run("outer 1").unwrap_err();
+        assert_eq!(error.message, "undefined variable: y");
+        let countdown = "make: {[(step: right) \
+            {(true: right, false: (this (right - step))) . (right <= step)}] . 1}";
+        machine.run(countdown).unwrap();
+        assert_eq!(machine.run("(make 3) . 10").unwrap(), number(1));
+    }
+
+    #[test]
+    fn test_undefined_names() {
+        let mut machine = Machine::new();
+        let error = machine.run("[(total: 3) (1 + totl)]").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Name);
+        assert_eq!(error.span, Some(Span::new(17, 21)));
+        assert_eq!(error.message, "undefined variable: totl, did you mean total?");
+        machine.run("fact: {right}").unwrap();
+        let error = machine.run("fatc").unwrap_err();
+        assert_eq!(error.message, "undefined variable: fatc, did you mean fact?");
+        let error = machine.run("[(abc: 1) (abd: 2) abx]").unwrap_err();
+        assert_eq!(error.message, "undefined variable: abx, did you mean abc or abd?");
+        let error = machine.run("{a: 1 + zz}").unwrap_err();
+        assert_eq!(error.message, "undefined variable: zz");
+        assert_eq!(error.span, Some(Span::new(8, 10)));
+        machine.run("f: {right + qq}").unwrap();
+        assert_eq!(machine.run("f 1").unwrap_err().span, Some(Span::new(12, 14)));
+        assert_eq!(machine.run_rpn("1 qq +").unwrap_err().span, Some(Span::new(2, 4)));
+    }
+
+    #[test]
+    fn test_native_operators() {
+        let mut machine = Machine::new();
+        let calls = Rc::new(std::cell::Cell::new(0));
+        let counter = calls.clone();
+        machine.register_operator("tick", OperatorType::Prefix, 14, Assoc::Left, move |operands| {
+            counter.set(counter.get() + 1);
+            Ok(operands[0].clone())
+        });
+        assert_eq!(machine.run("[(tick 1) (tick 2)]").unwrap().to_string(), "[1 2]");
+        assert_eq!(calls.get(), 2);
+        machine.register_number("avg", OperatorType::Infix, 12, |operands| {
+            Ok((&operands[0] + &operands[1]) / BigDecimal::from(2))
+        });
+        assert_eq!(machine.run("[(x: 3) (1 + x avg 5)] . 1").unwrap(), number(5));
+        assert_eq!(machine.run_rpn("2 4 avg").unwrap(), number(3));
+        assert_eq!(machine.to_rpn("2 avg 4").unwrap(), "2 4 avg");
+        machine.register_string("shout", OperatorType::Postfix, 14, |operands| {
+            Ok(operands[0].to_uppercase())
+        });
+        assert_eq!(machine.run("\"hi\" shout").unwrap(), Token::String("HI".to_string()));
+        let error = machine.run("1 shout").unwrap_err();
+        assert_eq!(error.message, "cannot apply shout to number");
+        machine.run("avg: {right}").unwrap();
+        assert_eq!(machine.run("avg 7").unwrap(), number(7));
+    }
+
+    #[test]
+    fn test_tracer() {
+        let mut machine = Machine::new();
+        machine.run("inc: {right + 1}").unwrap();
+        let steps = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = steps.clone();
+        machine.set_tracer(Some(Rc::new(move |step: &Step| {
+            let stack: Vec<String> = step.stack.iter().map(|token| token.to_string()).collect();
+            log.borrow_mut().push(format!("{} {} | {}", step.depth, step.token, stack.join(" ")));
+        })));
+        machine.run("2 * inc 3").unwrap();
+        let expected = [
+            "0 2 | 2", "0 3 | 2 3", "1 right | 3", "1 1 | 3 1", "1 + | 4", "0 inc | 2 4", "0 * | 8",
+        ];
+        assert_eq!(*steps.borrow(), expected);
+        machine.set_tracer(None);
+        machine.run("1").unwrap();
+        assert_eq!(steps.borrow().len(), expected.len());
+    }
+
+    #[test]
+    fn test_assertions() {
+        let mut machine = Machine::new();
+        assert_eq!(machine.run("assert 2 > 1").unwrap(), number(1));
+        let error = machine.run("assert 1 = 2").unwrap_err();
+        assert_eq!((error.kind, error.message.as_str()), (ErrorKind::Assertion, "got 0"));
+        let error = machine.run("\"too small\" assert 1 > 2").unwrap_err();
+        assert_eq!((error.kind, error.message.as_str()), (ErrorKind::Assertion, "too small"));
+
+        let caught = machine.run("\"type\" expect_error 1 + \"a\"").unwrap();
+        assert_eq!(caught.to_string(), "\"cannot apply + to number and string\"");
+        let message = machine.run("\"name\" expect_error x").unwrap();
+        assert_eq!(message.to_string(), "\"undefined variable: x\"");
+        let error = machine.run("\"name\" expect_error 1 + \"a\"").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assertion);
+        assert!(error.message.starts_with("expected name error, got type error"));
+        let error = machine.run("\"eval\" expect_error 1 + 1").unwrap_err();
+        assert_eq!(error.message, "expected eval error, got 2");
+
+        let tests = "[(y: 2) (\"uses y\" test {assert y = 2}) (\"fails\" test {assert 0})]";
+        machine.run(tests).unwrap();
+        machine.run("\"fails\" test {assert 1}").unwrap();
+        assert_eq!(machine.tests(), ["uses y", "fails"]);
+        assert_eq!(machine.run_test("uses y").unwrap(), number(1));
+        assert_eq!(machine.run_test("fails").unwrap(), number(1));
+        assert_eq!(machine.run_test("other").unwrap_err().kind, ErrorKind::Eval);
+        assert_eq!(machine.run("\"x\" test 1").unwrap_err().kind, ErrorKind::Type);
+    }
+
+    #[test]
+    fn test_fuzz_corpus() {
+        // What the fuzz targets once crashed on gives a value or an error.
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus");
+        for target in std::fs::read_dir(corpus).unwrap() {
+            for input in std::fs::read_dir(target.unwrap().path()).unwrap() {
+                let source = std::fs::read_to_string(input.unwrap().path()).unwrap();
+                let mut machine = Machine::new();
+                machine.set_fs_policy(FsPolicy::Deny);
+                let _ = machine.lexemes(&source);
+                let _ = machine.to_rpn(&source);
+                let _ = machine.run(&source);
+                let _ = machine.run_rpn(&source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stack_words() {
+        let mut machine = Machine::new();
+        assert_eq!(machine.run_rpn("1 0 -1 ! !").unwrap(), number(-1));
+        assert_eq!(machine.run_rpn("2 dup *").unwrap(), number(4));
+        assert_eq!(machine.run_rpn("1 2 swap -").unwrap(), number(1));
+        assert_eq!(machine.run_rpn("[ 1 dup ]").unwrap(), Token::List(vec![number(1), number(1)]));
+        assert_eq!(machine.run_rpn("1 u- 2 -").unwrap(), number(-3));
+        assert_eq!(machine.run_rpn("1 !").unwrap_err().message, "missing operand");
+        assert_eq!(machine.run_rpn("[ 1 ! ]").unwrap_err().message, "missing operand");
+        assert_eq!(machine.run_rpn("1 2 $$").unwrap_err().kind, ErrorKind::Lex);
+    }
+
+    #[test]
+    fn test_quote_round_trip() {
+        let mut machine = Machine::new();
+        let original = "line \"one\"\n\ttwo \\ three";
+        let rpn = machine.to_rpn(&quote(original)).unwrap();
+        assert_eq!(machine.run(&rpn).unwrap(), Token::String(original.to_string()));
+    }
 }
@@ -0,0 +1,66 @@
+//! The `handrit` command as a user runs it.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Runs `handrit` with `args`.
+fn handrit(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_handrit")).args(args).output().unwrap()
+}
+
+/// Writes `text` to a file named `name` in a directory of its own.
+fn file(name: &str, text: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("handrit-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, text).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_filter_skips_examples() {
+    let path = file("filter.hati", "1 + 1\n> 3\n\n\"adds\" test {assert (1 + 1 = 2)}\n");
+    let output = handrit(&["test", &path, "--filter", "adds"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "1 files, 1 passed, 0 failed\n");
+    assert!(output.status.success());
+    let output = handrit(&["test", &path]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("FAIL "), "{}", stdout);
+    assert!(stdout.ends_with("1 files, 1 passed, 1 failed\n"), "{}", stdout);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_denied_input_reads_as_expression() {
+    let secret = file("secret.hati", "1 + 1");
+    let missing = secret.replace("secret", "absent");
+    for policy in [["--deny-fs", ""], ["--allow-read", "/nonexistent"]] {
+        let run = |input: &str| {
+            let mut args = vec!["run", "-i", input, policy[0]];
+            args.extend(policy.iter().skip(1).filter(|arg| !arg.is_empty()));
+            let output = handrit(&args);
+            let printed = [output.stdout, output.stderr].concat();
+            String::from_utf8(printed).unwrap().replace("absent", "secret")
+        };
+        assert_eq!(run(&secret), run(&missing));
+    }
+    assert_eq!(handrit(&["run", "-i", &secret]).stdout, b"2\n");
+}
+
+#[test]
+fn test_repl_ends_with_input() {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_handrit"))
+        .arg("repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    repl.stdin.take().unwrap().write_all(b"1+1\n\n   \n2 * 3\n").unwrap();
+    let output = repl.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with("> 2\n> > > 6\n> "), "{}", stdout);
+    assert!(output.stderr.is_empty());
+    assert!(output.status.success());
+}
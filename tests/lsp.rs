@@ -0,0 +1,38 @@
+//! The language server run as `handrit lsp`, talking over its real stdin and
+//! stdout.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// `body` with the header the protocol frames it in.
+fn framed(body: &str) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+#[test]
+fn test_stdout_holds_only_messages() {
+    let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":
+        {"textDocument":{"uri":"file:///a.hati","text":"[(1 ; 2) (3 $ 4) (~ 5) (6 ?)]"}}}"#;
+    let exit = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+    let mut server = Command::new(env!("CARGO_BIN_EXE_handrit"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = server.stdin.take().unwrap();
+    stdin.write_all((framed(open) + &framed(exit)).as_bytes()).unwrap();
+    drop(stdin);
+    let output = server.wait_with_output().unwrap();
+    let mut rest = String::from_utf8(output.stdout).unwrap();
+    let mut messages = 0;
+    while !rest.is_empty() {
+        let header = rest.strip_prefix("Content-Length: ").expect(&rest);
+        let (length, body) = header.split_once("\r\n\r\n").unwrap();
+        let length: usize = length.parse().unwrap();
+        assert!(body[..length].starts_with('{'), "{}", body);
+        rest = body[length..].to_string();
+        messages += 1;
+    }
+    assert_eq!(messages, 1);
+}